@@ -0,0 +1,31 @@
+//! Feeds arbitrary bytes to a `CommandReader`, and decodes anything that
+//! looks like a frame. Nothing here should panic, whatever the input.
+//!
+//! Run with `cargo fuzz run --sanitizer=address fuzz_frame_parser -- -max_total_time=10`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use monotron_interface::{CommandReader, Message};
+
+/// A ping request, as framed by `CommandWriter`.
+const PING_FRAME: [u8; 5] = [0xC0, 0x01, 0xE1, 0xF1, 0xC0];
+
+fuzz_target!(|data: &[u8]| {
+    let mut cr = CommandReader::new();
+    for &b in data {
+        if let Ok(Some(payload)) = cr.push_byte(b) {
+            let _ = Message::parse(payload);
+        }
+    }
+    // Whatever state the reader was left in, it must pick up a good frame.
+    // The extra END closes off any half-received frame.
+    let _ = cr.push_byte(0xC0);
+    let mut found = false;
+    for &b in PING_FRAME.iter() {
+        if let Ok(Some(payload)) = cr.push_byte(b) {
+            assert_eq!(payload, &[0x01]);
+            found = true;
+        }
+    }
+    assert!(found);
+});
@@ -9,164 +9,8014 @@
 //! Request may be in flight at any one time.
 //!
 //! Requests:
+//!
+//! * PingExt(data: String)
 //! * OpenFile(filename: String, mode: Mode)
+//! * OpenFileV2(filename: String, mode: Mode, size_hint: u32)
 //! * CloseFile(handle: Handle)
-//! * Read(handle: Handle, offset: usize)
+//! * Read(handle: Handle, offset: u32)
+//! * ReadV2(handle: Handle, offset: u32, count: u16)
+//! * WriteFile(handle: Handle, offset: u32, data: String)
 //! * Checksum(handle: Handle)
+//! * ChecksumV2(handle: Handle)
 //! * OpenDir()
-//! * CloseDir(handle: Handle)
-//! * ReadDir(handle: Handle)
+//! * CloseDir(handle: DirHandle)
+//! * ReadDir(handle: DirHandle)
+//! * ReadDirAt(handle: DirHandle, index: u16)
+//! * CloseAll()
+//! * Sync(handle: Handle)
+//! * DeleteFile(filename: String)
+//! * RenameFile(old: String, new: String)
+//! * GetVersion()
+//! * SetBaudRate(baud: u32)
+//! * MakeDir(dirname: String)
+//! * ChangeDir(dirname: String)
+//! * GetWorkingDir()
+//! * SearchFiles(pattern: String)
+//! * ExecuteFile(filename: String)
+//! * LockFile(handle: Handle)
+//! * UnlockFile(handle: Handle)
+//! * SetVolumeLabel(label: String)
+//! * GetVolumeLabel()
+//! * GetFileAttributes(filename: String)
+//! * SetFileAttributes(filename: String, attrs: FileAttributes)
+//! * GetMemoryInfo()
+//! * DebugDump(address: u32, length: u32), with the `debug` feature
+//! * ScreenCapture()
+//!
 //! Confirmations:
+//!
+//! * PingExt(data: String)
 //! * Open(handle: Handle. error: Error)
+//! * OpenV2(handle: Handle. error: Error)
 //! * Close(error: Error)
 //! * Read(data: String, error: Error)
-//! * OpenDir(handle: Handle, error: Error)
+//! * ReadV2(offset: u32, data: String, error: Error)
+//! * WriteFile(error: Error)
+//! * Checksum(checksum: u32, error: Error)
+//! * ChecksumV2(algorithm: ChecksumAlgorithm, checksum: u32, error: Error)
+//! * OpenDir(handle: DirHandle, error: Error)
 //! * CloseDir(error: Error)
 //! * ReadDir(filename: String, size: u32, mtime: Timestamp, type: Type)
+//! * ReadDirEnd()
+//! * ReadDirAt(filename: String, size: u32, mtime: Timestamp, type: Type)
+//! * CloseAll(handles_closed: u8, error: Error)
+//! * Sync(error: Error)
+//! * DeleteFile(error: Error)
+//! * RenameFile(error: Error)
+//! * GetVersion(version: ProtocolVersion, error: Error)
+//! * SetBaudRate(accepted_baud: u32, error: Error)
+//! * MakeDir(error: Error)
+//! * ChangeDir(error: Error)
+//! * GetWorkingDir(path: String, error: Error)
+//! * SearchResult(filename: String, size: u32, mtime: Timestamp, type: Type)
+//! * SearchResultEnd()
+//! * ExecuteFile(exit_code: i8, error: Error)
+//! * LockFile(error: Error)
+//! * UnlockFile(error: Error)
+//! * SetVolumeLabel(error: Error)
+//! * GetVolumeLabel(label: String, error: Error)
+//! * GetFileAttributes(attrs: FileAttributes, error: Error)
+//! * SetFileAttributes(error: Error)
+//! * GetMemoryInfo(info: MemoryInfo, error: Error)
+//! * DebugDump(address: u32, data: String, error: Error), with the `debug`
+//!   feature
+//! * ScreenCapture(chunk: String, error: Error)
+//!
 //! Indications:
+//!
 //! * Keypress(utf8_byte: u8)
+//! * Keypresses(utf8_bytes: String)
+//! * AudioStop(reason: AudioStopReason)
+//! * Interrupt(code: InterruptCode)
+//! * Battery(status: BatteryIndication)
+//! * Joystick(state: JoystickState)
+//! * ScreenResize(size: ScreenResizeIndication)
+//! * Mouse(movement: MouseIndication)
+//!
+//! The PC replies to a ReadDir request with ReadDirEnd once there are no more
+//! entries. Older PCs instead send a ReadDir Confirmation with the error
+//! `FileNotFound`, which `parse_read_dir_cfm` also accepts, but new ones
+//! should use ReadDirEnd so the end of a directory can't be mistaken for an
+//! error.
+//!
+//! The PC replies to a SearchFiles request with a SearchResult Confirmation
+//! for each matching entry, then SearchResultEnd. In the same way, it replies
+//! to a DebugDump request with a DebugDump Confirmation for each chunk of
+//! memory, then one with no data, and to a ScreenCapture request with
+//! ScreenCapture Confirmations carrying the screen a chunk at a time, then one
+//! with an empty chunk.
+//!
+//! Every frame starts and ends with an `END` byte, and carries an opcode, the
+//! payload and a big-endian CRC-16/X25 of the opcode and payload. `END` and
+//! `ESC` bytes inside the frame are escaped, as per SLIP. Multi-byte integers
+//! are sent big-endian. Where a Confirmation carries an error, a zero byte
+//! means success.
 #![no_std]
 
 extern crate crc;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "std")]
+extern crate std;
 
-#[derive(Debug, Copy, Clone)]
+use core::convert::TryFrom;
+use core::fmt;
+use core::iter;
+
+/// Things that can go wrong, either locally or on the PC.
+///
+/// New error codes may be added to the protocol, so code outside this crate
+/// must have a wildcard arm when matching on an `Error`. Treat an unknown
+/// error as a failure of the request that produced it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
     BadChecksum,
     BadHeader,
     BufferOverflow,
     FileNotFound,
     BadOffset,
+    /// The PC's disk failed while reading or writing.
+    IoError,
+    /// The PC won't let that file be changed.
+    PermissionDenied,
+    /// There is already a file with that name.
+    AlreadyExists,
+    /// The file isn't a program the Monotron can run.
+    ExecFormat,
+    /// Someone else holds a lock on that file.
+    Locked,
+    /// That handle isn't open, or isn't in the right state.
+    InvalidHandle,
 }
 
-#[derive(Debug)]
+/// Things that can go wrong in a session, above the level of single frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProtocolError {
+    /// No whole frame was waiting to be checked.
+    NoFrame,
+    /// A frame arrived with this opcode, which wasn't the one expected.
+    UnknownOpcode(u8),
+}
+
+/// Identifies an open file on the PC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Handle(u8);
+
+/// Identifies an open directory on the PC. Directory handles are allocated
+/// separately from file `Handle`s, so the two cannot be mixed up.
+///
+/// ```
+/// # use monotron_interface::{CommandWriter, DirHandle};
+/// let mut cw = CommandWriter::new();
+/// cw.send_close_dir_req(DirHandle::new(1)).unwrap();
+/// ```
+///
+/// Passing a file `Handle` to a directory operation does not compile:
+///
+/// ```compile_fail
+/// # use monotron_interface::{CommandWriter, Handle};
+/// let mut cw = CommandWriter::new();
+/// cw.send_close_dir_req(Handle::new(1)).unwrap();
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirHandle(u8);
+
+/// The ways in which a whole file can be checksummed.
+///
+/// The PC may answer a ChecksumV2 request with an algorithm this crate
+/// doesn't know, which is kept as `Unknown`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChecksumAlgorithm {
+    /// CRC-32/ISO-HDLC, as used by zip and Ethernet. Marked CRC-32 on
+    /// <https://crccalc.com/>.
+    Crc32Iso,
+    /// Adler-32, as used by zlib.
+    Adler32,
+    Unknown(u8),
+}
+
+/// The algorithm the PC uses to answer a Checksum request.
+pub const FILE_CHECKSUM: ChecksumAlgorithm = ChecksumAlgorithm::Crc32Iso;
+
+/// The ways in which a file can be opened.
+///
+/// More modes may be added later. When matching on a `Mode` you don't
+/// recognise, refuse to open the file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Mode {
+    Read,
+    Write,
+    ReadWrite,
+    /// Every write goes at the end of the file, whatever offset is asked for.
+    Append,
+}
+
+/// A calendar date and time, as used for file modification times.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year_from_1970: u8,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub days: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+/// A revision of the wire protocol, as sent in a GetVersion Confirmation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// How memory is being used, as sent in a GetMemoryInfo Confirmation. All
+/// the sizes are in bytes, and neither `free_ram` nor `stack_usage` can be
+/// more than `total_ram`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MemoryInfo {
+    pub total_ram: u32,
+    pub free_ram: u32,
+    pub stack_usage: u32,
+}
+
+/// Something that knows the time, such as the Monotron's real-time clock.
+pub trait RtcSource {
+    fn now(&self) -> Timestamp;
+}
+
+/// What sort of thing a directory entry refers to.
+///
+/// Other types (such as links) may be added later. Entries of a type you
+/// don't recognise can be skipped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// One entry from a directory listing. The name refers to the frame it was
+/// decoded from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a [u8],
+    pub size: u32,
+    pub mtime: Timestamp,
+    pub file_type: FileType,
+}
+
+/// The attribute bits of a file, with the same values as in a FAT
+/// directory entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FileAttributes(u8);
+
+/// A decoded frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Message<'a> {
+    Request(Request<'a>),
+    Confirmation(Confirmation<'a>),
+    Indication(Indication<'a>),
+}
+
+/// Sent by the Monotron to the PC.
+///
+/// New requests may be added to the protocol. A PC which receives a request
+/// it doesn't handle should still send a Confirmation, so the Monotron isn't
+/// left waiting.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Request<'a> {
+    Ping,
+    OpenFile {
+        filename: &'a [u8],
+        mode: Mode,
+    },
+    CloseFile {
+        handle: Handle,
+    },
+    Read {
+        handle: Handle,
+        offset: u32,
+    },
+    WriteFile {
+        handle: Handle,
+        offset: u32,
+        data: &'a [u8],
+    },
+    Checksum {
+        handle: Handle,
+    },
+    OpenDir,
+    CloseDir {
+        handle: DirHandle,
+    },
+    ReadDir {
+        handle: DirHandle,
+    },
+    /// Close every open file and directory.
+    CloseAll,
+    /// A Ping carrying data, which the PC sends straight back.
+    PingExt {
+        data: &'a [u8],
+    },
+    /// A Read of at most `count` bytes, whose Confirmation says which offset
+    /// it is for.
+    ReadV2 {
+        handle: Handle,
+        offset: u32,
+        count: u16,
+    },
+    /// An OpenFile with a hint of how big the file will get, so the PC can
+    /// allocate space up front. A `size_hint` of 0 means unknown.
+    OpenFileV2 {
+        filename: &'a [u8],
+        mode: Mode,
+        size_hint: u32,
+    },
+    /// A Checksum where the PC picks the algorithm, and says which it used.
+    ChecksumV2 {
+        handle: Handle,
+    },
+    /// Write anything buffered for the file out to disk.
+    Sync {
+        handle: Handle,
+    },
+    DeleteFile {
+        filename: &'a [u8],
+    },
+    RenameFile {
+        old: &'a [u8],
+        new: &'a [u8],
+    },
+    /// Ask which version of the protocol the PC speaks.
+    GetVersion,
+    /// Change the speed of the serial link. Both ends switch once the
+    /// Confirmation has been sent.
+    SetBaudRate {
+        baud: u32,
+    },
+    /// Fetch a given entry from an open directory, counting from 0, so a
+    /// listing can be picked up where it left off.
+    ReadDirAt {
+        handle: DirHandle,
+        index: u16,
+    },
+    MakeDir {
+        dirname: &'a [u8],
+    },
+    /// Make `dirname` the current directory, which OpenDir lists.
+    ChangeDir {
+        dirname: &'a [u8],
+    },
+    /// Ask for the path of the current directory.
+    GetWorkingDir,
+    /// Find the entries in the current directory whose names match
+    /// `pattern`, where `*` matches any run of characters and `?` any one
+    /// character.
+    SearchFiles {
+        pattern: &'a [u8],
+    },
+    /// Run the program in `filename`, and report how it exited.
+    ExecuteFile {
+        filename: &'a [u8],
+    },
+    /// Take an advisory lock on an open file. The lock is released when the
+    /// file is closed.
+    LockFile {
+        handle: Handle,
+    },
+    UnlockFile {
+        handle: Handle,
+    },
+    /// Give the PC's disk a new volume label.
+    SetVolumeLabel {
+        label: &'a [u8],
+    },
+    GetVolumeLabel,
+    GetFileAttributes {
+        filename: &'a [u8],
+    },
+    /// Replace all the attributes of `filename` with `attrs`.
+    SetFileAttributes {
+        filename: &'a [u8],
+        attrs: FileAttributes,
+    },
+    /// Ask how much memory is in use.
+    GetMemoryInfo,
+    /// Ask for a dump of `length` bytes of memory, starting at `address`.
+    #[cfg(feature = "debug")]
+    DebugDump {
+        address: u32,
+        length: u32,
+    },
+    /// Ask for a copy of what is on the screen.
+    ScreenCapture,
+}
+
+/// Sent by the PC to the Monotron, in reply to a `Request`. An `error` of
+/// `None` means the `Request` was successful.
+///
+/// There is a Confirmation for every `Request`, so this grows in step with
+/// `Request`. An unexpected Confirmation should be treated as a failure of
+/// the outstanding `Request`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Confirmation<'a> {
+    Ping,
+    OpenFile {
+        handle: Handle,
+        error: Option<Error>,
+    },
+    CloseFile {
+        error: Option<Error>,
+    },
+    Read {
+        data: &'a [u8],
+        error: Option<Error>,
+    },
+    WriteFile {
+        error: Option<Error>,
+    },
+    Checksum {
+        checksum: u32,
+        error: Option<Error>,
+    },
+    OpenDir {
+        handle: DirHandle,
+        error: Option<Error>,
+    },
+    CloseDir {
+        error: Option<Error>,
+    },
+    /// The end of the directory is marked with `Error::FileNotFound`.
+    ReadDir {
+        entry: Option<DirEntry<'a>>,
+        error: Option<Error>,
+    },
+    /// Every handle given out before a CloseAll is now invalid.
+    CloseAll {
+        handles_closed: u8,
+        error: Option<Error>,
+    },
+    /// Carries the data from the `PingExt` request, unchanged.
+    PingExt {
+        data: &'a [u8],
+    },
+    /// Like `Read`, but with the offset from the request.
+    ReadV2 {
+        offset: u32,
+        data: &'a [u8],
+        error: Option<Error>,
+    },
+    OpenFileV2 {
+        handle: Handle,
+        error: Option<Error>,
+    },
+    ChecksumV2 {
+        algorithm: ChecksumAlgorithm,
+        checksum: u32,
+        error: Option<Error>,
+    },
+    /// There are no more entries in the directory.
+    ReadDirEnd,
+    /// `Error::IoError` means the data may not have reached the disk.
+    Sync {
+        error: Option<Error>,
+    },
+    DeleteFile {
+        error: Option<Error>,
+    },
+    /// `Error::AlreadyExists` means there is already a file with the new
+    /// name.
+    RenameFile {
+        error: Option<Error>,
+    },
+    GetVersion {
+        version: ProtocolVersion,
+        error: Option<Error>,
+    },
+    /// The rate both ends will switch to, which may not be the one asked
+    /// for.
+    SetBaudRate {
+        accepted_baud: u32,
+        error: Option<Error>,
+    },
+    /// `Error::BadOffset` means there is no entry with that index.
+    ReadDirAt {
+        entry: Option<DirEntry<'a>>,
+        error: Option<Error>,
+    },
+    /// `Error::AlreadyExists` means there is already a file or directory
+    /// with that name.
+    MakeDir {
+        error: Option<Error>,
+    },
+    /// `Error::FileNotFound` means there is no such directory.
+    ChangeDir {
+        error: Option<Error>,
+    },
+    GetWorkingDir {
+        path: &'a [u8],
+        error: Option<Error>,
+    },
+    /// One of the entries matching a `SearchFiles` request.
+    SearchResult {
+        entry: Option<DirEntry<'a>>,
+        error: Option<Error>,
+    },
+    /// There are no more entries matching the `SearchFiles` request.
+    SearchResultEnd,
+    /// `exit_code` only means something if there is no `error`, and is 0
+    /// otherwise.
+    ExecuteFile {
+        exit_code: i8,
+        error: Option<Error>,
+    },
+    /// `Error::Locked` means another handle holds a lock on the file.
+    LockFile {
+        error: Option<Error>,
+    },
+    /// `Error::InvalidHandle` means the file wasn't locked.
+    UnlockFile {
+        error: Option<Error>,
+    },
+    SetVolumeLabel {
+        error: Option<Error>,
+    },
+    GetVolumeLabel {
+        label: &'a [u8],
+        error: Option<Error>,
+    },
+    GetFileAttributes {
+        attrs: FileAttributes,
+        error: Option<Error>,
+    },
+    /// `Error::PermissionDenied` means the attributes can't be changed, for
+    /// example because the disk is write-protected.
+    SetFileAttributes {
+        error: Option<Error>,
+    },
+    GetMemoryInfo {
+        info: MemoryInfo,
+        error: Option<Error>,
+    },
+    /// One chunk of a memory dump. An empty `data` ends the dump.
+    #[cfg(feature = "debug")]
+    DebugDump {
+        address: u32,
+        data: &'a [u8],
+        error: Option<Error>,
+    },
+    /// One chunk of the screen. An empty `chunk` ends the capture.
+    ScreenCapture {
+        chunk: &'a [u8],
+        error: Option<Error>,
+    },
+}
+
+/// Why audio playback stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AudioStopReason {
+    /// The whole file was played.
+    EndOfFile,
+    /// Playback was stopped before the end of the file.
+    Stopped,
+    /// Playback failed, with an error code from the player.
+    Error(u8),
+}
+
+/// Something that happened to the system, as sent in an Interrupt
+/// indication.
+///
+/// Codes this crate doesn't know are kept as `Unknown`, so the PC can add new
+/// ones.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InterruptCode {
+    /// The disk was removed, so every open handle is now invalid.
+    DiskEjected,
+    /// The battery is running low.
+    PowerLow,
+    /// The battery is about to run out, so save any work now.
+    PowerCritical,
+    Unknown(u8),
+}
+
+/// The state of the battery, as sent in a Battery indication.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BatteryIndication {
+    /// How full the battery is, from 0 to 100.
+    pub percent: u8,
+    /// The battery voltage, which is never less than
+    /// `BatteryIndication::MIN_MILLIVOLTS`.
+    pub millivolts: u16,
+    pub charging: bool,
+}
+
+/// Which joystick directions and buttons are held down, as sent in a
+/// Joystick indication.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct JoystickState {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub fire: bool,
+}
+
+/// The new size of the screen, in characters, as sent in a ScreenResize
+/// indication.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScreenResizeIndication {
+    pub cols: u8,
+    pub rows: u8,
+}
+
+/// Which mouse buttons are held down.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+/// How far the mouse has moved since the last Mouse indication, and which
+/// buttons are now held down.
+///
+/// The deltas are wider than they are on the wire, so that a PC can't
+/// silently wrap a large movement. Only -128 to 127 can be sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MouseIndication {
+    pub delta_x: i16,
+    pub delta_y: i16,
+    pub buttons: MouseButtons,
+}
+
+/// Sent by the PC to the Monotron at any time.
+///
+/// New indications may be added later. As nothing is waiting for them,
+/// unknown indications can safely be ignored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Indication<'a> {
+    Keypress(u8),
+    /// Several keypresses at once, in the order they were pressed.
+    Keypresses(&'a [u8]),
+    /// Audio playback has finished.
+    AudioStop(AudioStopReason),
+    /// Something happened to the system. See `InterruptCode`.
+    Interrupt(InterruptCode),
+    /// The state of the battery has changed.
+    Battery(BatteryIndication),
+    /// The joystick has moved, or a button was pressed or released.
+    Joystick(JoystickState),
+    /// The screen has changed size.
+    ScreenResize(ScreenResizeIndication),
+    /// The mouse has moved, or a button was pressed or released.
+    Mouse(MouseIndication),
+}
+
+#[derive(Debug, Clone)]
 pub struct CommandWriter {
-    bytes: [u8; 32],
+    bytes: [u8; MAX_PAYLOAD],
     sent: usize,
     count: usize,
     had_escape: bool,
     crc: u16,
+    prepared: bool,
+    total_bytes_sent: u64,
+    frames_sent: u32,
 }
 
-const PING_REQ: u8 = 0x01;
-const PING_CFM: u8 = 0x81;
-const END: u8 = 0xC0;
-const ESC: u8 = 0xDB;
-const ESC_END: u8 = 0xDC;
-const ESC_ESC: u8 = 0xDD;
+/// Who sends frames with a given opcode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpcodeDirection {
+    /// Sent by the Monotron.
+    Request,
+    /// Sent by the PC, in reply to a Request.
+    Confirmation,
+    /// Sent by the PC at any time.
+    Indication,
+    /// Not in any of the ranges in `opcodes`.
+    Unknown,
+}
 
-impl CommandWriter {
-    pub fn new() -> CommandWriter {
-        CommandWriter {
-            bytes: [0u8; 32],
-            sent: 0,
-            count: 0,
-            had_escape: false,
-            crc: 0,
+/// Writes a buffer to a file, using as many WriteFile requests as it takes.
+///
+/// Call `next_request` to build the next request, send it, and pass the
+/// Confirmation to `handle_confirmation`. Calling `next_request` again
+/// without a successful Confirmation rebuilds the same request, so it can be
+/// retransmitted.
+#[derive(Debug)]
+pub struct WriteAllSession<'a> {
+    handle: Handle,
+    data: &'a [u8],
+    offset: usize,
+    chunk_size: usize,
+}
+
+/// Decodes frames from a stream of bytes.
+#[derive(Debug)]
+pub struct CommandReader {
+    bytes: [u8; MAX_PAYLOAD + CRC16_BYTES],
+    count: usize,
+    state: ReadState,
+    bytes_consumed: u64,
+    frames_received: u32,
+    checksum_errors: u32,
+    consecutive_bad_checksums: u32,
+    overflow_occurred: bool,
+    frame_ready: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReadState {
+    WaitingForStart,
+    InFrame,
+    InEscape,
+}
+
+const MAX_PAYLOAD: usize = 32;
+
+/// Every frame starts and ends with an `END` byte.
+pub const FRAME_DELIMITER_BYTES: usize = 2;
+
+/// The CRC-16/X25 at the end of every frame.
+pub const CRC16_BYTES: usize = 2;
+
+/// The bytes added to every payload to make a frame, before any escaping.
+pub const MIN_FRAME_OVERHEAD: usize = FRAME_DELIMITER_BYTES + CRC16_BYTES;
+
+/// The size of the smallest frame, which has just an opcode.
+pub const MIN_FRAME_SIZE: usize = MIN_FRAME_OVERHEAD + 1;
+
+/// The most keypresses `CommandWriter::send_keypress_batch` puts in one
+/// frame.
+pub const MAX_KEYPRESSES_PER_FRAME: usize = MAX_PAYLOAD - 1;
+
+/// The most data that fits in one WriteFile request.
+pub const MAX_WRITE_CHUNK: usize = MAX_PAYLOAD - 6;
+
+/// The longest path that fits in one GetWorkingDir Confirmation.
+pub const MAX_PATH_LEN: usize = MAX_PAYLOAD - 3;
+
+/// The most memory that fits in one DebugDump Confirmation.
+#[cfg(feature = "debug")]
+pub const MAX_DEBUG_DUMP_CHUNK: usize = MAX_PAYLOAD - 6;
+
+/// The most screen data sent in one ScreenCapture Confirmation.
+pub const MAX_SCREEN_CAPTURE_CHUNK: usize = 24;
+
+/// The narrowest screen a ScreenResize indication can give.
+pub const MIN_SCREEN_COLS: u8 = 1;
+/// The shortest screen a ScreenResize indication can give.
+pub const MIN_SCREEN_ROWS: u8 = 1;
+/// The widest screen a ScreenResize indication can give.
+pub const MAX_SCREEN_COLS: u8 = 255;
+/// The tallest screen a ScreenResize indication can give.
+pub const MAX_SCREEN_ROWS: u8 = 255;
+
+/// The longest volume label, which is the most a FAT filesystem can hold.
+pub const MAX_VOLUME_LABEL_LEN: usize = 11;
+
+/// The revision of the wire protocol this crate speaks. See `CHANGELOG.md`.
+pub const PROTOCOL_REVISION: &str = "0.1.0";
+
+/// The oldest revision of the wire protocol this crate can talk to.
+pub const MIN_COMPATIBLE_REVISION: &str = "0.1.0";
+
+/// The first byte of every frame says what the frame contains.
+///
+/// * Requests are `0x01` to `0x6F`.
+/// * Each Confirmation is its Request's opcode with the top bit set, so
+///   Confirmations are `0x81` to `0xEF`. The exceptions are
+///   `READ_DIR_END_CFM` and `SEARCH_RESULT_END_CFM`.
+/// * Indications are `0xF0` to `0xFF`.
+///
+/// `0x00`, `0x70` to `0x7F` and `0x80` are not used.
+///
+/// Every opcode here was introduced in protocol revision 0.1.0, unless its
+/// documentation says otherwise. See `CHANGELOG.md`.
+pub mod opcodes {
+    /// Check the link is working.
+    pub const PING_REQ: u8 = 0x01;
+    /// Open a file by name.
+    pub const OPEN_FILE_REQ: u8 = 0x02;
+    /// Close an open file.
+    pub const CLOSE_FILE_REQ: u8 = 0x03;
+    /// Read from an open file at a given offset.
+    pub const READ_REQ: u8 = 0x04;
+    /// Write to an open file at a given offset.
+    pub const WRITE_FILE_REQ: u8 = 0x05;
+    /// Checksum an entire open file.
+    pub const CHECKSUM_REQ: u8 = 0x07;
+    /// Open the current directory for listing.
+    pub const OPEN_DIR_REQ: u8 = 0x08;
+    /// Close an open directory.
+    pub const CLOSE_DIR_REQ: u8 = 0x09;
+    /// Fetch the next entry from an open directory.
+    pub const READ_DIR_REQ: u8 = 0x0A;
+    /// Close every open file and directory.
+    pub const CLOSE_ALL_REQ: u8 = 0x0B;
+    /// A Ping carrying data to be echoed back.
+    pub const PING_REQ_EXT: u8 = 0x0C;
+    /// Read up to a given number of bytes from an open file, with the offset
+    /// echoed in the Confirmation.
+    pub const READ_REQ_V2: u8 = 0x0D;
+    /// Open a file by name, with a hint of its eventual size.
+    pub const OPEN_FILE_REQ_V2: u8 = 0x0E;
+    /// Checksum an entire open file, with an algorithm chosen by the PC.
+    pub const CHECKSUM_REQ_V2: u8 = 0x0F;
+    /// Flush an open file to disk.
+    pub const SYNC_REQ: u8 = 0x10;
+    /// Delete a file by name.
+    pub const DELETE_FILE_REQ: u8 = 0x11;
+    /// Give a file a new name.
+    pub const RENAME_FILE_REQ: u8 = 0x12;
+    /// Find out which protocol version the PC speaks.
+    pub const GET_VERSION_REQ: u8 = 0x13;
+    /// Change the speed of the serial link.
+    pub const SET_BAUD_RATE_REQ: u8 = 0x14;
+    /// Fetch the entry with a given index from an open directory.
+    pub const READ_DIR_AT_REQ: u8 = 0x15;
+    /// Make a directory by name.
+    pub const MAKE_DIR_REQ: u8 = 0x16;
+    /// Change the current directory.
+    pub const CHANGE_DIR_REQ: u8 = 0x17;
+    /// Find out the path of the current directory.
+    pub const GET_WORKING_DIR_REQ: u8 = 0x18;
+    /// Find entries in the current directory matching a wildcard pattern.
+    pub const SEARCH_FILES_REQ: u8 = 0x19;
+    /// Run a program by name.
+    pub const EXECUTE_FILE_REQ: u8 = 0x1B;
+    /// Take an advisory lock on an open file.
+    pub const LOCK_FILE_REQ: u8 = 0x1C;
+    /// Release a lock taken with `LOCK_FILE_REQ`.
+    pub const UNLOCK_FILE_REQ: u8 = 0x1D;
+    /// Change the volume label of the PC's disk.
+    pub const SET_VOLUME_LABEL_REQ: u8 = 0x1E;
+    /// Find out the volume label of the PC's disk.
+    pub const GET_VOLUME_LABEL_REQ: u8 = 0x1F;
+    /// Find out the attributes of a file.
+    pub const GET_FILE_ATTRIBUTES_REQ: u8 = 0x20;
+    /// Change the attributes of a file.
+    pub const SET_FILE_ATTRIBUTES_REQ: u8 = 0x21;
+    /// Find out how much memory is in use.
+    pub const GET_MEMORY_INFO_REQ: u8 = 0x22;
+    /// Dump a range of memory, for debugging. Only handled with the `debug`
+    /// feature.
+    pub const DEBUG_DUMP_REQ: u8 = 0x23;
+    /// Capture what is on the screen.
+    pub const SCREEN_CAPTURE_REQ: u8 = 0x24;
+
+    /// Reply to `PING_REQ`.
+    pub const PING_CFM: u8 = 0x81;
+    /// Reply to `OPEN_FILE_REQ`.
+    pub const OPEN_FILE_CFM: u8 = 0x82;
+    /// Reply to `CLOSE_FILE_REQ`.
+    pub const CLOSE_FILE_CFM: u8 = 0x83;
+    /// Reply to `READ_REQ`.
+    pub const READ_CFM: u8 = 0x84;
+    /// Reply to `WRITE_FILE_REQ`.
+    pub const WRITE_FILE_CFM: u8 = 0x85;
+    /// Reply to `CHECKSUM_REQ`.
+    pub const CHECKSUM_CFM: u8 = 0x87;
+    /// Reply to `OPEN_DIR_REQ`.
+    pub const OPEN_DIR_CFM: u8 = 0x88;
+    /// Reply to `CLOSE_DIR_REQ`.
+    pub const CLOSE_DIR_CFM: u8 = 0x89;
+    /// Reply to `READ_DIR_REQ`.
+    pub const READ_DIR_CFM: u8 = 0x8A;
+    /// Reply to `CLOSE_ALL_REQ`.
+    pub const CLOSE_ALL_CFM: u8 = 0x8B;
+    /// Reply to `PING_REQ_EXT`.
+    pub const PING_CFM_EXT: u8 = 0x8C;
+    /// Reply to `READ_REQ_V2`. Both this and `READ_CFM` are supported; a
+    /// future major revision may drop `READ_REQ` and `READ_CFM`.
+    pub const READ_CFM_V2: u8 = 0x8D;
+    /// Reply to `OPEN_FILE_REQ_V2`. The PC must handle both `OPEN_FILE_REQ`
+    /// and `OPEN_FILE_REQ_V2`.
+    pub const OPEN_FILE_CFM_V2: u8 = 0x8E;
+    /// Reply to `CHECKSUM_REQ_V2`.
+    pub const CHECKSUM_CFM_V2: u8 = 0x8F;
+    /// Reply to `READ_DIR_REQ` when there are no more entries. This uses the
+    /// Confirmation opcode of the unused Request `0x06`.
+    pub const READ_DIR_END_CFM: u8 = 0x86;
+    /// Reply to `SYNC_REQ`.
+    pub const SYNC_CFM: u8 = 0x90;
+    /// Reply to `DELETE_FILE_REQ`.
+    pub const DELETE_FILE_CFM: u8 = 0x91;
+    /// Reply to `RENAME_FILE_REQ`.
+    pub const RENAME_FILE_CFM: u8 = 0x92;
+    /// Reply to `GET_VERSION_REQ`.
+    pub const GET_VERSION_CFM: u8 = 0x93;
+    /// Reply to `SET_BAUD_RATE_REQ`.
+    pub const SET_BAUD_RATE_CFM: u8 = 0x94;
+    /// Reply to `READ_DIR_AT_REQ`.
+    pub const READ_DIR_AT_CFM: u8 = 0x95;
+    /// Reply to `MAKE_DIR_REQ`.
+    pub const MAKE_DIR_CFM: u8 = 0x96;
+    /// Reply to `CHANGE_DIR_REQ`.
+    pub const CHANGE_DIR_CFM: u8 = 0x97;
+    /// Reply to `GET_WORKING_DIR_REQ`.
+    pub const GET_WORKING_DIR_CFM: u8 = 0x98;
+    /// Reply to `SEARCH_FILES_REQ`, once for each matching entry.
+    pub const SEARCH_RESULT_CFM: u8 = 0x99;
+    /// Reply to `SEARCH_FILES_REQ` when there are no more matching entries.
+    /// This uses the Confirmation opcode of the unused Request `0x1A`.
+    pub const SEARCH_RESULT_END_CFM: u8 = 0x9A;
+    /// Reply to `EXECUTE_FILE_REQ`.
+    pub const EXECUTE_FILE_CFM: u8 = 0x9B;
+    /// Reply to `LOCK_FILE_REQ`.
+    pub const LOCK_FILE_CFM: u8 = 0x9C;
+    /// Reply to `UNLOCK_FILE_REQ`.
+    pub const UNLOCK_FILE_CFM: u8 = 0x9D;
+    /// Reply to `SET_VOLUME_LABEL_REQ`.
+    pub const SET_VOLUME_LABEL_CFM: u8 = 0x9E;
+    /// Reply to `GET_VOLUME_LABEL_REQ`.
+    pub const GET_VOLUME_LABEL_CFM: u8 = 0x9F;
+    /// Reply to `GET_FILE_ATTRIBUTES_REQ`.
+    pub const GET_FILE_ATTRIBUTES_CFM: u8 = 0xA0;
+    /// Reply to `SET_FILE_ATTRIBUTES_REQ`.
+    pub const SET_FILE_ATTRIBUTES_CFM: u8 = 0xA1;
+    /// Reply to `GET_MEMORY_INFO_REQ`.
+    pub const GET_MEMORY_INFO_CFM: u8 = 0xA2;
+    /// Reply to `DEBUG_DUMP_REQ`, once for each chunk.
+    pub const DEBUG_DUMP_CFM: u8 = 0xA3;
+    /// Reply to `SCREEN_CAPTURE_REQ`, once for each chunk.
+    pub const SCREEN_CAPTURE_CFM: u8 = 0xA4;
+
+    /// A key was pressed on the PC.
+    pub const KEYPRESS_IND: u8 = 0xF0;
+    /// Several keys were pressed on the PC.
+    pub const KEYPRESS_BATCH_IND: u8 = 0xF1;
+    /// Audio playback has finished.
+    pub const AUDIO_STOP_IND: u8 = 0xF2;
+    /// Something happened to the system. This can arrive at any time,
+    /// including between a Request and its Confirmation.
+    pub const INTERRUPT_IND: u8 = 0xF3;
+    /// The state of the battery has changed.
+    pub const BATTERY_IND: u8 = 0xF4;
+    /// The state of the joystick has changed.
+    pub const JOYSTICK_IND: u8 = 0xF5;
+    /// The screen has changed size.
+    pub const SCREEN_RESIZE_IND: u8 = 0xF6;
+    /// The mouse has moved, or a button was pressed or released.
+    pub const MOUSE_IND: u8 = 0xF7;
+}
+
+use opcodes::*;
+
+/// The raw byte values used on the wire, for code (such as the Monotron
+/// firmware) that wants the wire format without the types in this crate.
+pub mod wire {
+    pub use super::opcodes::*;
+
+    /// Starts and ends every frame.
+    pub const END: u8 = 0xC0;
+    /// Starts an escape sequence inside a frame.
+    pub const ESC: u8 = 0xDB;
+    /// `ESC` then `ESC_END` stands for an `END` byte in the frame.
+    pub const ESC_END: u8 = 0xDC;
+    /// `ESC` then `ESC_ESC` stands for an `ESC` byte in the frame.
+    pub const ESC_ESC: u8 = 0xDD;
+
+    /// The error byte in a Confirmation for a successful request.
+    pub const ERROR_SUCCESS: u8 = 0x00;
+    /// `Error::BadChecksum`.
+    pub const ERROR_BAD_CHECKSUM: u8 = 0x01;
+    /// `Error::BadHeader`.
+    pub const ERROR_BAD_HEADER: u8 = 0x02;
+    /// `Error::BufferOverflow`.
+    pub const ERROR_BUFFER_OVERFLOW: u8 = 0x03;
+    /// `Error::FileNotFound`.
+    pub const ERROR_FILE_NOT_FOUND: u8 = 0x04;
+    /// `Error::BadOffset`.
+    pub const ERROR_BAD_OFFSET: u8 = 0x05;
+    /// `Error::IoError`.
+    pub const ERROR_IO_ERROR: u8 = 0x06;
+    /// `Error::PermissionDenied`.
+    pub const ERROR_PERMISSION_DENIED: u8 = 0x07;
+    /// `Error::AlreadyExists`.
+    pub const ERROR_ALREADY_EXISTS: u8 = 0x08;
+    /// `Error::ExecFormat`.
+    pub const ERROR_EXEC_FORMAT: u8 = 0x09;
+    /// `Error::Locked`.
+    pub const ERROR_LOCKED: u8 = 0x0A;
+    /// `Error::InvalidHandle`.
+    pub const ERROR_INVALID_HANDLE: u8 = 0x0B;
+}
+
+/// The types most users need, without the helpers and constants.
+///
+/// ```
+/// use monotron_interface::prelude::*;
+///
+/// let mut cw = CommandWriter::new();
+/// let _cr = CommandReader::new();
+/// cw.send_close_file_req(Handle::new(1)).unwrap();
+/// cw.send_close_dir_req(DirHandle::new(2)).unwrap();
+/// let _mode = Mode::ReadWrite;
+/// let _error = Error::FileNotFound;
+/// let _protocol_error = ProtocolError::NoFrame;
+/// let mtime = Timestamp {
+///     year_from_1970: 49,
+///     month: 1,
+///     days: 1,
+///     hours: 0,
+///     minutes: 0,
+///     seconds: 0,
+/// };
+/// let _entry = DirEntry {
+///     name: b"A.TXT",
+///     size: 0,
+///     mtime,
+///     file_type: FileType::File,
+/// };
+/// let _attrs = FileAttributes::READ_ONLY | FileAttributes::HIDDEN;
+/// let _version = ProtocolVersion {
+///     major: 1,
+///     minor: 0,
+///     patch: 0,
+/// };
+/// let _messages = [
+///     Message::Request(Request::Ping),
+///     Message::Confirmation(Confirmation::Ping),
+///     Message::Indication(Indication::Keypress(b'A')),
+/// ];
+/// ```
+pub mod prelude {
+    pub use crate::{
+        CommandReader, CommandWriter, Confirmation, DirEntry, DirHandle, Error, FileAttributes,
+        FileType, Handle, Indication, Message, Mode, ProtocolError, ProtocolVersion, Request,
+        Timestamp,
+    };
+}
+
+use wire::{END, ESC, ESC_END, ESC_ESC};
+
+impl Error {
+    /// The byte used for this error in a Confirmation.
+    pub fn to_wire(self) -> u8 {
+        match self {
+            Error::BadChecksum => wire::ERROR_BAD_CHECKSUM,
+            Error::BadHeader => wire::ERROR_BAD_HEADER,
+            Error::BufferOverflow => wire::ERROR_BUFFER_OVERFLOW,
+            Error::FileNotFound => wire::ERROR_FILE_NOT_FOUND,
+            Error::BadOffset => wire::ERROR_BAD_OFFSET,
+            Error::IoError => wire::ERROR_IO_ERROR,
+            Error::PermissionDenied => wire::ERROR_PERMISSION_DENIED,
+            Error::AlreadyExists => wire::ERROR_ALREADY_EXISTS,
+            Error::ExecFormat => wire::ERROR_EXEC_FORMAT,
+            Error::Locked => wire::ERROR_LOCKED,
+            Error::InvalidHandle => wire::ERROR_INVALID_HANDLE,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.sent = 0;
-        self.count = 0;
+    /// The error for a byte in a Confirmation. Gives `None` for the success
+    /// byte and for unknown bytes.
+    pub fn from_wire(byte: u8) -> Option<Error> {
+        match byte {
+            wire::ERROR_BAD_CHECKSUM => Some(Error::BadChecksum),
+            wire::ERROR_BAD_HEADER => Some(Error::BadHeader),
+            wire::ERROR_BUFFER_OVERFLOW => Some(Error::BufferOverflow),
+            wire::ERROR_FILE_NOT_FOUND => Some(Error::FileNotFound),
+            wire::ERROR_BAD_OFFSET => Some(Error::BadOffset),
+            wire::ERROR_IO_ERROR => Some(Error::IoError),
+            wire::ERROR_PERMISSION_DENIED => Some(Error::PermissionDenied),
+            wire::ERROR_ALREADY_EXISTS => Some(Error::AlreadyExists),
+            wire::ERROR_EXEC_FORMAT => Some(Error::ExecFormat),
+            wire::ERROR_LOCKED => Some(Error::Locked),
+            wire::ERROR_INVALID_HANDLE => Some(Error::InvalidHandle),
+            _ => None,
+        }
     }
+}
 
-    pub fn prep_for_send(&mut self) {
-        self.sent = 0;
-        // See https://crccalc.com/, marked CRC-16/X25
-        self.crc = crc::crc16::checksum_x25(&self.bytes[0..self.count]);
+/// Gives the byte used for the error in a Confirmation.
+///
+/// ```
+/// # use monotron_interface::Error;
+/// assert_eq!(u8::from(Error::FileNotFound), 0x04);
+/// ```
+impl From<Error> for u8 {
+    fn from(e: Error) -> u8 {
+        e.to_wire()
     }
+}
 
-    pub fn send_ping_req(&mut self) {
-        self.bytes[0] = PING_REQ;
-        self.count = 1;
-        self.prep_for_send();
+/// Decodes the error byte in a Confirmation. The success byte, and bytes
+/// this crate doesn't know about, are rejected.
+///
+/// ```
+/// # use monotron_interface::Error;
+/// # use std::convert::TryFrom;
+/// assert_eq!(Error::try_from(0x04), Ok(Error::FileNotFound));
+/// assert_eq!(Error::try_from(0x00), Err(()));
+/// ```
+impl TryFrom<u8> for Error {
+    type Error = ();
+
+    fn try_from(b: u8) -> Result<Error, ()> {
+        Error::from_wire(b).ok_or(())
     }
+}
 
-    pub fn send_ping_cfm(&mut self) {
-        self.bytes[0] = PING_CFM;
-        self.count = 1;
-        self.prep_for_send();
+/// Lets `?` be used on our functions inside a `core::fmt::Write`
+/// implementation. The detail of the error is lost.
+///
+/// ```
+/// # use monotron_interface::{parse_close_cfm, Error};
+/// use std::fmt::{self, Write};
+///
+/// fn show(f: &mut dyn Write, payload: &[u8]) -> fmt::Result {
+///     parse_close_cfm(payload)?;
+///     write!(f, "closed")
+/// }
+///
+/// let mut s = String::new();
+/// assert!(show(&mut s, &[0x83, 0x00]).is_ok());
+/// assert!(show(&mut s, &[0x83, 0x04]).is_err());
+/// ```
+impl From<Error> for fmt::Error {
+    fn from(_e: Error) -> fmt::Error {
+        fmt::Error
     }
+}
 
-    fn escape_and_send(&mut self, to_send: u8) -> u8 {
-        if !need_escape(to_send) {
-            self.sent += 1;
-            to_send
-        } else {
-            if !self.had_escape {
-                self.had_escape = true;
-                ESC
-            } else {
-                self.sent += 1;
-                self.had_escape = false;
-                escape(to_send)
-            }
+impl Handle {
+    /// The handle sent when there is no open file.
+    pub const NULL: Handle = Handle(0);
+
+    pub fn new(value: u8) -> Handle {
+        Handle(value)
+    }
+
+    pub fn is_valid(self) -> bool {
+        self != Handle::NULL
+    }
+
+    pub fn to_wire(self) -> u8 {
+        self.0
+    }
+
+    pub fn from_wire(byte: u8) -> Handle {
+        Handle(byte)
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+impl DirHandle {
+    /// The handle sent when there is no open directory.
+    pub const NULL: DirHandle = DirHandle(0);
+
+    pub fn new(value: u8) -> DirHandle {
+        DirHandle(value)
+    }
+
+    pub fn is_valid(self) -> bool {
+        self != DirHandle::NULL
+    }
+
+    pub fn to_wire(self) -> u8 {
+        self.0
+    }
+
+    pub fn from_wire(byte: u8) -> DirHandle {
+        DirHandle(byte)
+    }
+}
+
+impl fmt::Display for DirHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dir #{}", self.0)
+    }
+}
+
+impl ChecksumAlgorithm {
+    /// Checksum `data`, which should be the entire contents of a file.
+    /// Gives `None` for an `Unknown` algorithm.
+    pub fn compute(self, data: &[u8]) -> Option<u32> {
+        match self {
+            ChecksumAlgorithm::Crc32Iso => Some(crc::crc32::checksum_ieee(data)),
+            ChecksumAlgorithm::Adler32 => Some(adler32(data)),
+            ChecksumAlgorithm::Unknown(_) => None,
         }
     }
 
-    pub fn get_byte(&mut self) -> Option<u8> {
-        if self.sent == 0 {
-            self.sent += 1;
-            Some(END)
-        } else if self.sent <= self.count {
-            let to_send = self.bytes[self.sent - 1];
-            Some(self.escape_and_send(to_send))
-        } else if self.sent == (self.count + 1) {
-            let to_send = (self.crc >> 8) as u8;
-            Some(self.escape_and_send(to_send))
-        } else if self.sent == (self.count + 2) {
-            let to_send = (self.crc >> 0) as u8;
-            Some(self.escape_and_send(to_send))
-        } else if self.sent == (self.count + 3) {
-            self.sent += 1;
-            Some(END)
-        } else {
-            None
+    pub fn to_wire(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32Iso => 0x01,
+            ChecksumAlgorithm::Adler32 => 0x02,
+            ChecksumAlgorithm::Unknown(byte) => byte,
+        }
+    }
+
+    pub fn from_wire(byte: u8) -> ChecksumAlgorithm {
+        match byte {
+            0x01 => ChecksumAlgorithm::Crc32Iso,
+            0x02 => ChecksumAlgorithm::Adler32,
+            x => ChecksumAlgorithm::Unknown(x),
         }
     }
 }
 
-fn need_escape(byte: u8) -> bool {
-    match byte {
-        END => true,
-        ESC => true,
-        _ => false,
+impl InterruptCode {
+    pub fn to_wire(self) -> u8 {
+        match self {
+            InterruptCode::DiskEjected => 0x01,
+            InterruptCode::PowerLow => 0x02,
+            InterruptCode::PowerCritical => 0x03,
+            InterruptCode::Unknown(byte) => byte,
+        }
+    }
+
+    pub fn from_wire(byte: u8) -> InterruptCode {
+        match byte {
+            0x01 => InterruptCode::DiskEjected,
+            0x02 => InterruptCode::PowerLow,
+            0x03 => InterruptCode::PowerCritical,
+            x => InterruptCode::Unknown(x),
+        }
     }
 }
 
-fn escape(byte: u8) -> u8 {
-    match byte {
-        END => ESC_END,
-        ESC => ESC_ESC,
-        x => x,
+impl BatteryIndication {
+    /// Any reading below this is taken to be garbage.
+    pub const MIN_MILLIVOLTS: u16 = 3000;
+
+    /// Set in the flags byte if the battery is charging. The other bits are
+    /// reserved, and sent as 0.
+    const FLAG_CHARGING: u8 = 0x01;
+
+    /// Is the percentage no more than 100, and the voltage at least
+    /// `MIN_MILLIVOLTS`?
+    pub fn is_valid(&self) -> bool {
+        self.percent <= 100 && self.millivolts >= BatteryIndication::MIN_MILLIVOLTS
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+impl JoystickState {
+    /// Bits 0 to 4 are up, down, left, right and fire. Bits 5 to 7 are
+    /// reserved, and always 0.
+    pub fn to_wire(self) -> u8 {
+        (self.up as u8)
+            | (self.down as u8) << 1
+            | (self.left as u8) << 2
+            | (self.right as u8) << 3
+            | (self.fire as u8) << 4
+    }
 
-    #[test]
-    fn basic_ping_req() {
-        let mut cw = CommandWriter::new();
-        cw.send_ping_req();
-        assert_eq!(cw.get_byte(), Some(END));
-        assert_eq!(cw.get_byte(), Some(PING_REQ));
-        assert_eq!(cw.get_byte(), Some(0xE1));
-        assert_eq!(cw.get_byte(), Some(0xF1));
-        assert_eq!(cw.get_byte(), Some(END));
-        assert_eq!(cw.get_byte(), None);
+    /// Gives `Error::BadHeader` if any of the reserved bits are set.
+    pub fn from_wire(byte: u8) -> Result<JoystickState, Error> {
+        if byte & 0xE0 != 0 {
+            return Err(Error::BadHeader);
+        }
+        Ok(JoystickState {
+            up: byte & 0x01 != 0,
+            down: byte & 0x02 != 0,
+            left: byte & 0x04 != 0,
+            right: byte & 0x08 != 0,
+            fire: byte & 0x10 != 0,
+        })
     }
+}
 
-    #[test]
-    fn basic_ping_cfm() {
-        let mut cw = CommandWriter::new();
-        cw.send_ping_cfm();
-        assert_eq!(cw.get_byte(), Some(END));
-        assert_eq!(cw.get_byte(), Some(PING_CFM));
-        assert_eq!(cw.get_byte(), Some(0x65));
-        assert_eq!(cw.get_byte(), Some(0xF9));
-        assert_eq!(cw.get_byte(), Some(END));
-        assert_eq!(cw.get_byte(), None);
+impl MouseButtons {
+    pub const LEFT: MouseButtons = MouseButtons(0x01);
+    pub const RIGHT: MouseButtons = MouseButtons(0x02);
+    pub const MIDDLE: MouseButtons = MouseButtons(0x04);
+
+    /// No buttons held down.
+    pub fn empty() -> MouseButtons {
+        MouseButtons(0)
+    }
+
+    /// Are all the buttons in `other` held down?
+    pub fn contains(self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Press the buttons in `other`.
+    pub fn insert(&mut self, other: MouseButtons) {
+        self.0 |= other.0;
+    }
+
+    /// Release the buttons in `other`.
+    pub fn remove(&mut self, other: MouseButtons) {
+        self.0 &= !other.0;
+    }
+
+    pub fn to_wire(self) -> u8 {
+        self.0
+    }
+
+    /// Gives `Error::BadHeader` if any bit other than the three buttons is
+    /// set.
+    pub fn from_wire(byte: u8) -> Result<MouseButtons, Error> {
+        if byte & !0x07 != 0 {
+            return Err(Error::BadHeader);
+        }
+        Ok(MouseButtons(byte))
+    }
+}
+
+impl core::ops::BitOr for MouseButtons {
+    type Output = MouseButtons;
+
+    fn bitor(self, other: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 | other.0)
+    }
+}
+
+impl ScreenResizeIndication {
+    /// Gives `Error::BadHeader` if the screen would be smaller than
+    /// `MIN_SCREEN_COLS` by `MIN_SCREEN_ROWS`.
+    pub fn new(cols: u8, rows: u8) -> Result<ScreenResizeIndication, Error> {
+        if cols < MIN_SCREEN_COLS || rows < MIN_SCREEN_ROWS {
+            return Err(Error::BadHeader);
+        }
+        Ok(ScreenResizeIndication { cols, rows })
+    }
+}
+
+impl AudioStopReason {
+    /// The catch-all for a failure of the audio hardware.
+    pub const HARDWARE_FAILURE: AudioStopReason = AudioStopReason::Error(0xFF);
+
+    /// The reason byte and error code sent on the wire. The code is 0
+    /// unless playback failed.
+    pub fn to_wire(self) -> [u8; 2] {
+        match self {
+            AudioStopReason::EndOfFile => [0x00, 0x00],
+            AudioStopReason::Stopped => [0x01, 0x00],
+            AudioStopReason::Error(code) => [0x02, code],
+        }
+    }
+
+    pub fn from_wire(reason: u8, code: u8) -> Result<AudioStopReason, Error> {
+        match (reason, code) {
+            (0x00, 0x00) => Ok(AudioStopReason::EndOfFile),
+            (0x01, 0x00) => Ok(AudioStopReason::Stopped),
+            (0x02, code) => Ok(AudioStopReason::Error(code)),
+            _ => Err(Error::BadHeader),
+        }
+    }
+}
+
+impl Timestamp {
+    /// The number of bytes a `Timestamp` takes up in a frame.
+    pub const WIRE_LEN: usize = 6;
+
+    /// The time now, according to `rtc`.
+    pub fn from_rtc<R: RtcSource>(rtc: &R) -> Timestamp {
+        rtc.now()
+    }
+
+    pub fn to_wire(&self) -> [u8; Timestamp::WIRE_LEN] {
+        [
+            self.year_from_1970,
+            self.month,
+            self.days,
+            self.hours,
+            self.minutes,
+            self.seconds,
+        ]
+    }
+
+    pub fn from_wire(bytes: &[u8; Timestamp::WIRE_LEN]) -> Result<Timestamp, Error> {
+        let ts = Timestamp {
+            year_from_1970: bytes[0],
+            month: bytes[1],
+            days: bytes[2],
+            hours: bytes[3],
+            minutes: bytes[4],
+            seconds: bytes[5],
+        };
+        if ts.month < 1
+            || ts.month > 12
+            || ts.days < 1
+            || ts.days > 31
+            || ts.hours > 23
+            || ts.minutes > 59
+            || ts.seconds > 59
+        {
+            return Err(Error::BadHeader);
+        }
+        Ok(ts)
+    }
+
+    /// The earliest time a FAT filesystem can record, 1980-01-01 00:00:00.
+    pub const FAT_EPOCH: Timestamp = Timestamp {
+        year_from_1970: 10,
+        month: 1,
+        days: 1,
+        hours: 0,
+        minutes: 0,
+        seconds: 0,
+    };
+
+    /// The latest time a FAT filesystem can record, 2107-12-31 23:59:58.
+    pub const FAT_MAX: Timestamp = Timestamp {
+        year_from_1970: 137,
+        month: 12,
+        days: 31,
+        hours: 23,
+        minutes: 59,
+        seconds: 58,
+    };
+
+    /// Pack into the `(date, time)` fields of a FAT directory entry, which
+    /// are `YYYYYYYMMMMDDDDD` and `HHHHHMMMMMMSSSSS`. FAT only counts
+    /// seconds in twos, so odd seconds are rounded down, and times outside
+    /// `FAT_EPOCH` to `FAT_MAX` are clamped to the nearest end. Any other
+    /// field outside the range `from_wire` accepts is clamped to that range,
+    /// so it can't spill into the next field.
+    pub fn to_fat_date_time(&self) -> (u16, u16) {
+        let ts = if self.year_from_1970 < Timestamp::FAT_EPOCH.year_from_1970 {
+            Timestamp::FAT_EPOCH
+        } else if self.year_from_1970 > Timestamp::FAT_MAX.year_from_1970 {
+            Timestamp::FAT_MAX
+        } else {
+            *self
+        };
+        let year = u16::from(ts.year_from_1970 - Timestamp::FAT_EPOCH.year_from_1970);
+        let month = u16::from(ts.month.clamp(1, 12));
+        let days = u16::from(ts.days.clamp(1, 31));
+        let hours = u16::from(ts.hours.min(23));
+        let minutes = u16::from(ts.minutes.min(59));
+        let seconds = u16::from(ts.seconds.min(59));
+        let date = (year << 9) | (month << 5) | days;
+        let time = (hours << 11) | (minutes << 5) | (seconds / 2);
+        (date, time)
+    }
+
+    /// Unpack the `date` and `time` fields of a FAT directory entry. Gives
+    /// `Error::BadHeader` if any field is out of range.
+    pub fn from_fat_date_time(date: u16, time: u16) -> Result<Timestamp, Error> {
+        Timestamp::from_wire(&[
+            Timestamp::FAT_EPOCH.year_from_1970 + (date >> 9) as u8,
+            ((date >> 5) & 0x0F) as u8,
+            (date & 0x1F) as u8,
+            (time >> 11) as u8,
+            ((time >> 5) & 0x3F) as u8,
+            (time & 0x1F) as u8 * 2,
+        ])
+    }
+}
+
+impl MemoryInfo {
+    /// Do the free RAM and stack usage both fit in the total RAM?
+    pub fn is_valid(&self) -> bool {
+        self.free_ram <= self.total_ram && self.stack_usage <= self.total_ram
+    }
+}
+
+impl ProtocolVersion {
+    /// The version this crate speaks, which is `PROTOCOL_REVISION`.
+    pub const CURRENT: ProtocolVersion = ProtocolVersion {
+        major: 0,
+        minor: 1,
+        patch: 0,
+    };
+
+    /// Can something speaking this version talk to something speaking
+    /// `other`? They must have the same major version, and neither may be
+    /// older than `MIN_COMPATIBLE_REVISION`.
+    ///
+    /// A host checking a version from a GetVersion Confirmation should use
+    /// `ProtocolVersion::CURRENT.is_compatible_with`, which gives the same
+    /// answer as the free `is_compatible_with` does for a revision string.
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        let min = match parse_revision(MIN_COMPATIBLE_REVISION) {
+            Some(min) => min,
+            None => return false,
+        };
+        self.major == other.major && self.as_tuple() >= min && other.as_tuple() >= min
+    }
+
+    fn as_tuple(&self) -> (u32, u32, u32) {
+        (
+            u32::from(self.major),
+            u32::from(self.minor),
+            u32::from(self.patch),
+        )
+    }
+}
+
+impl FileAttributes {
+    /// The file can't be written to.
+    pub const READ_ONLY: FileAttributes = FileAttributes(0x01);
+    /// The file isn't normally listed.
+    pub const HIDDEN: FileAttributes = FileAttributes(0x02);
+    /// The file belongs to the operating system.
+    pub const SYSTEM: FileAttributes = FileAttributes(0x04);
+    /// The file has changed since it was last backed up.
+    pub const ARCHIVE: FileAttributes = FileAttributes(0x20);
+
+    /// No attributes set.
+    pub fn empty() -> FileAttributes {
+        FileAttributes(0)
+    }
+
+    /// Are all the bits in `other` set?
+    pub fn contains(self, other: FileAttributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set the bits in `other`.
+    pub fn insert(&mut self, other: FileAttributes) {
+        self.0 |= other.0;
+    }
+
+    /// Clear the bits in `other`.
+    pub fn remove(&mut self, other: FileAttributes) {
+        self.0 &= !other.0;
+    }
+
+    pub fn to_wire(self) -> u8 {
+        self.0
+    }
+
+    /// Bits this crate doesn't know about are kept, so they survive a round
+    /// trip through a Get and a Set.
+    pub fn from_wire(byte: u8) -> FileAttributes {
+        FileAttributes(byte)
+    }
+}
+
+impl core::ops::BitOr for FileAttributes {
+    type Output = FileAttributes;
+
+    fn bitor(self, other: FileAttributes) -> FileAttributes {
+        FileAttributes(self.0 | other.0)
+    }
+}
+
+impl FileType {
+    pub fn to_wire(self) -> u8 {
+        match self {
+            FileType::File => 0x00,
+            FileType::Directory => 0x01,
+        }
+    }
+
+    pub fn from_wire(byte: u8) -> Result<FileType, Error> {
+        match byte {
+            0x00 => Ok(FileType::File),
+            0x01 => Ok(FileType::Directory),
+            _ => Err(Error::BadHeader),
+        }
+    }
+}
+
+impl Mode {
+    pub fn wire_byte(self) -> u8 {
+        match self {
+            Mode::Read => 0x01,
+            Mode::Write => 0x02,
+            Mode::ReadWrite => 0x03,
+            Mode::Append => 0x04,
+        }
+    }
+
+    pub fn from_wire(byte: u8) -> Result<Mode, Error> {
+        match byte {
+            0x01 => Ok(Mode::Read),
+            0x02 => Ok(Mode::Write),
+            0x03 => Ok(Mode::ReadWrite),
+            0x04 => Ok(Mode::Append),
+            _ => Err(Error::BadHeader),
+        }
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Decode a payload, as returned by `CommandReader::push_byte`.
+    pub fn parse(payload: &'a [u8]) -> Result<Message<'a>, Error> {
+        let (&opcode, args) = payload.split_first().ok_or(Error::BadHeader)?;
+        let message = match opcode {
+            PING_REQ => Message::Request(Request::Ping),
+            OPEN_FILE_REQ => {
+                let (filename, rest) = split_filename(args)?;
+                match rest {
+                    [mode] => Message::Request(Request::OpenFile {
+                        filename,
+                        mode: Mode::from_wire(*mode)?,
+                    }),
+                    _ => return Err(Error::BadHeader),
+                }
+            }
+            CLOSE_FILE_REQ => match args {
+                [handle] => Message::Request(Request::CloseFile {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_REQ => match args {
+                [handle, _, _, _, _] => Message::Request(Request::Read {
+                    handle: Handle::from_wire(*handle),
+                    offset: parse_u32_be(args, 1)?.0,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            WRITE_FILE_REQ => match args {
+                [handle, _, _, _, _, data @ ..] => Message::Request(Request::WriteFile {
+                    handle: Handle::from_wire(*handle),
+                    offset: parse_u32_be(args, 1)?.0,
+                    data,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CHECKSUM_REQ => match args {
+                [handle] => Message::Request(Request::Checksum {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            OPEN_DIR_REQ => match args {
+                [] => Message::Request(Request::OpenDir),
+                _ => return Err(Error::BadHeader),
+            },
+            CLOSE_DIR_REQ => match args {
+                [handle] => Message::Request(Request::CloseDir {
+                    handle: DirHandle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_DIR_REQ => match args {
+                [handle] => Message::Request(Request::ReadDir {
+                    handle: DirHandle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CLOSE_ALL_REQ => match args {
+                [] => Message::Request(Request::CloseAll),
+                _ => return Err(Error::BadHeader),
+            },
+            PING_REQ_EXT => Message::Request(Request::PingExt { data: args }),
+            READ_REQ_V2 => match args {
+                [handle, _, _, _, _, _, _] => {
+                    let (offset, next) = parse_u32_be(args, 1)?;
+                    let (count, _) = parse_u16_be(args, next)?;
+                    if count == 0 {
+                        return Err(Error::BadHeader);
+                    }
+                    Message::Request(Request::ReadV2 {
+                        handle: Handle::from_wire(*handle),
+                        offset,
+                        count,
+                    })
+                }
+                _ => return Err(Error::BadHeader),
+            },
+            OPEN_FILE_REQ_V2 => {
+                let (filename, rest) = split_filename(args)?;
+                match rest {
+                    [mode, _, _, _, _] => Message::Request(Request::OpenFileV2 {
+                        filename,
+                        mode: Mode::from_wire(*mode)?,
+                        size_hint: parse_u32_be(rest, 1)?.0,
+                    }),
+                    _ => return Err(Error::BadHeader),
+                }
+            }
+            CHECKSUM_REQ_V2 => match args {
+                [handle] => Message::Request(Request::ChecksumV2 {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SYNC_REQ => match args {
+                [handle] => Message::Request(Request::Sync {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            DELETE_FILE_REQ => match split_filename(args)? {
+                (filename, []) => Message::Request(Request::DeleteFile { filename }),
+                _ => return Err(Error::BadHeader),
+            },
+            RENAME_FILE_REQ => {
+                let (old, rest) = split_filename(args)?;
+                match split_filename(rest)? {
+                    (new, []) => Message::Request(Request::RenameFile { old, new }),
+                    _ => return Err(Error::BadHeader),
+                }
+            }
+            GET_VERSION_REQ => match args {
+                [] => Message::Request(Request::GetVersion),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_BAUD_RATE_REQ => match args {
+                [_, _, _, _] => Message::Request(Request::SetBaudRate {
+                    baud: parse_u32_be(args, 0)?.0,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_DIR_AT_REQ => match args {
+                [handle, _, _] => Message::Request(Request::ReadDirAt {
+                    handle: DirHandle::from_wire(*handle),
+                    index: parse_u16_be(args, 1)?.0,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            MAKE_DIR_REQ => match split_filename(args)? {
+                (dirname, []) => Message::Request(Request::MakeDir { dirname }),
+                _ => return Err(Error::BadHeader),
+            },
+            CHANGE_DIR_REQ => match split_filename(args)? {
+                (dirname, []) => Message::Request(Request::ChangeDir { dirname }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_WORKING_DIR_REQ => match args {
+                [] => Message::Request(Request::GetWorkingDir),
+                _ => return Err(Error::BadHeader),
+            },
+            SEARCH_FILES_REQ => match split_filename(args)? {
+                (pattern, []) => Message::Request(Request::SearchFiles { pattern }),
+                _ => return Err(Error::BadHeader),
+            },
+            EXECUTE_FILE_REQ => match split_filename(args)? {
+                (filename, []) => Message::Request(Request::ExecuteFile { filename }),
+                _ => return Err(Error::BadHeader),
+            },
+            LOCK_FILE_REQ => match args {
+                [handle] => Message::Request(Request::LockFile {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            UNLOCK_FILE_REQ => match args {
+                [handle] => Message::Request(Request::UnlockFile {
+                    handle: Handle::from_wire(*handle),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_VOLUME_LABEL_REQ => match split_filename(args)? {
+                (label, []) => Message::Request(Request::SetVolumeLabel { label }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_VOLUME_LABEL_REQ => match args {
+                [] => Message::Request(Request::GetVolumeLabel),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_FILE_ATTRIBUTES_REQ => match split_filename(args)? {
+                (filename, []) => Message::Request(Request::GetFileAttributes { filename }),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_FILE_ATTRIBUTES_REQ => match split_filename(args)? {
+                (filename, [attrs]) => Message::Request(Request::SetFileAttributes {
+                    filename,
+                    attrs: FileAttributes::from_wire(*attrs),
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_MEMORY_INFO_REQ => match args {
+                [] => Message::Request(Request::GetMemoryInfo),
+                _ => return Err(Error::BadHeader),
+            },
+            #[cfg(feature = "debug")]
+            DEBUG_DUMP_REQ => match args {
+                [_, _, _, _, _, _, _, _] => Message::Request(Request::DebugDump {
+                    address: parse_u32_be(args, 0)?.0,
+                    length: parse_u32_be(args, 4)?.0,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SCREEN_CAPTURE_REQ => match args {
+                [] => Message::Request(Request::ScreenCapture),
+                _ => return Err(Error::BadHeader),
+            },
+            PING_CFM => Message::Confirmation(Confirmation::Ping),
+            OPEN_FILE_CFM => match args {
+                [error, handle] => Message::Confirmation(Confirmation::OpenFile {
+                    handle: Handle::from_wire(*handle),
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CLOSE_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::CloseFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_CFM => {
+                let (&error, data) = args.split_first().ok_or(Error::BadHeader)?;
+                Message::Confirmation(Confirmation::Read {
+                    data,
+                    error: error_from_wire(error)?,
+                })
+            }
+            WRITE_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::WriteFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CHECKSUM_CFM => match args {
+                [error, _, _, _, _] => Message::Confirmation(Confirmation::Checksum {
+                    checksum: parse_u32_be(args, 1)?.0,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            OPEN_DIR_CFM => match args {
+                [error, handle] => Message::Confirmation(Confirmation::OpenDir {
+                    handle: DirHandle::from_wire(*handle),
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CLOSE_DIR_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::CloseDir {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_DIR_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                let error = error_from_wire(error)?;
+                let entry = match error {
+                    None => Some(parse_dir_entry(rest)?),
+                    Some(_) => None,
+                };
+                Message::Confirmation(Confirmation::ReadDir { entry, error })
+            }
+            CLOSE_ALL_CFM => match args {
+                [error, handles_closed] => Message::Confirmation(Confirmation::CloseAll {
+                    handles_closed: *handles_closed,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            PING_CFM_EXT => Message::Confirmation(Confirmation::PingExt { data: args }),
+            READ_CFM_V2 => match args {
+                [error, _, _, _, _, data @ ..] => Message::Confirmation(Confirmation::ReadV2 {
+                    offset: parse_u32_be(args, 1)?.0,
+                    data,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            OPEN_FILE_CFM_V2 => match args {
+                [error, handle] => Message::Confirmation(Confirmation::OpenFileV2 {
+                    handle: Handle::from_wire(*handle),
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CHECKSUM_CFM_V2 => match args {
+                [error, algorithm, _, _, _, _] => Message::Confirmation(Confirmation::ChecksumV2 {
+                    algorithm: ChecksumAlgorithm::from_wire(*algorithm),
+                    checksum: parse_u32_be(args, 2)?.0,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_DIR_END_CFM => match args {
+                [] => Message::Confirmation(Confirmation::ReadDirEnd),
+                _ => return Err(Error::BadHeader),
+            },
+            SYNC_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::Sync {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            DELETE_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::DeleteFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            RENAME_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::RenameFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_VERSION_CFM => match args {
+                [error, major, minor, patch] => Message::Confirmation(Confirmation::GetVersion {
+                    version: ProtocolVersion {
+                        major: *major,
+                        minor: *minor,
+                        patch: *patch,
+                    },
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_BAUD_RATE_CFM => match args {
+                [error, _, _, _, _] => Message::Confirmation(Confirmation::SetBaudRate {
+                    accepted_baud: parse_u32_be(args, 1)?.0,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            READ_DIR_AT_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                let error = error_from_wire(error)?;
+                let entry = match error {
+                    None => Some(parse_dir_entry(rest)?),
+                    Some(_) => None,
+                };
+                Message::Confirmation(Confirmation::ReadDirAt { entry, error })
+            }
+            MAKE_DIR_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::MakeDir {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            CHANGE_DIR_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::ChangeDir {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_WORKING_DIR_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                match split_filename(rest)? {
+                    (path, []) => Message::Confirmation(Confirmation::GetWorkingDir {
+                        path,
+                        error: error_from_wire(error)?,
+                    }),
+                    _ => return Err(Error::BadHeader),
+                }
+            }
+            SEARCH_RESULT_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                let error = error_from_wire(error)?;
+                let entry = match error {
+                    None => Some(parse_dir_entry(rest)?),
+                    Some(_) => None,
+                };
+                Message::Confirmation(Confirmation::SearchResult { entry, error })
+            }
+            SEARCH_RESULT_END_CFM => match args {
+                [] => Message::Confirmation(Confirmation::SearchResultEnd),
+                _ => return Err(Error::BadHeader),
+            },
+            EXECUTE_FILE_CFM => match args {
+                [error, exit_code] => Message::Confirmation(Confirmation::ExecuteFile {
+                    exit_code: *exit_code as i8,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            LOCK_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::LockFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            UNLOCK_FILE_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::UnlockFile {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_VOLUME_LABEL_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::SetVolumeLabel {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_VOLUME_LABEL_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                match split_filename(rest)? {
+                    (label, []) => Message::Confirmation(Confirmation::GetVolumeLabel {
+                        label,
+                        error: error_from_wire(error)?,
+                    }),
+                    _ => return Err(Error::BadHeader),
+                }
+            }
+            GET_FILE_ATTRIBUTES_CFM => match args {
+                [error, attrs] => Message::Confirmation(Confirmation::GetFileAttributes {
+                    attrs: FileAttributes::from_wire(*attrs),
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SET_FILE_ATTRIBUTES_CFM => match args {
+                [error] => Message::Confirmation(Confirmation::SetFileAttributes {
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            GET_MEMORY_INFO_CFM => {
+                let (&error, rest) = args.split_first().ok_or(Error::BadHeader)?;
+                Message::Confirmation(Confirmation::GetMemoryInfo {
+                    info: parse_memory_info(rest)?,
+                    error: error_from_wire(error)?,
+                })
+            }
+            #[cfg(feature = "debug")]
+            DEBUG_DUMP_CFM => match args {
+                [error, _, _, _, _, data @ ..] => Message::Confirmation(Confirmation::DebugDump {
+                    address: parse_u32_be(args, 1)?.0,
+                    data,
+                    error: error_from_wire(*error)?,
+                }),
+                _ => return Err(Error::BadHeader),
+            },
+            SCREEN_CAPTURE_CFM => {
+                let (&error, chunk) = args.split_first().ok_or(Error::BadHeader)?;
+                Message::Confirmation(Confirmation::ScreenCapture {
+                    chunk,
+                    error: error_from_wire(error)?,
+                })
+            }
+            KEYPRESS_IND => match args {
+                [byte] => Message::Indication(Indication::Keypress(*byte)),
+                _ => return Err(Error::BadHeader),
+            },
+            KEYPRESS_BATCH_IND => match args {
+                [] => return Err(Error::BadHeader),
+                bytes => Message::Indication(Indication::Keypresses(bytes)),
+            },
+            INTERRUPT_IND => match args {
+                [code] => {
+                    Message::Indication(Indication::Interrupt(InterruptCode::from_wire(*code)))
+                }
+                _ => return Err(Error::BadHeader),
+            },
+            BATTERY_IND => match args {
+                [_, _, _, _] => {
+                    Message::Indication(Indication::Battery(parse_battery_status(args)?))
+                }
+                _ => return Err(Error::BadHeader),
+            },
+            JOYSTICK_IND => match args {
+                [state] => {
+                    Message::Indication(Indication::Joystick(JoystickState::from_wire(*state)?))
+                }
+                _ => return Err(Error::BadHeader),
+            },
+            MOUSE_IND => Message::Indication(Indication::Mouse(parse_mouse_indication(payload)?)),
+            SCREEN_RESIZE_IND => match args {
+                [cols, rows] => Message::Indication(Indication::ScreenResize(
+                    ScreenResizeIndication::new(*cols, *rows)?,
+                )),
+                _ => return Err(Error::BadHeader),
+            },
+            AUDIO_STOP_IND => match args {
+                [reason, code] => Message::Indication(Indication::AudioStop(
+                    AudioStopReason::from_wire(*reason, *code)?,
+                )),
+                _ => return Err(Error::BadHeader),
+            },
+            _ => return Err(Error::BadHeader),
+        };
+        Ok(message)
+    }
+}
+
+impl<'a> Request<'a> {
+    /// The opcode this request is sent with.
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Request::Ping => PING_REQ,
+            Request::PingExt { .. } => PING_REQ_EXT,
+            Request::OpenFile { .. } => OPEN_FILE_REQ,
+            Request::CloseFile { .. } => CLOSE_FILE_REQ,
+            Request::Read { .. } => READ_REQ,
+            Request::WriteFile { .. } => WRITE_FILE_REQ,
+            Request::Checksum { .. } => CHECKSUM_REQ,
+            Request::OpenDir => OPEN_DIR_REQ,
+            Request::CloseDir { .. } => CLOSE_DIR_REQ,
+            Request::ReadDir { .. } => READ_DIR_REQ,
+            Request::CloseAll => CLOSE_ALL_REQ,
+            Request::ReadV2 { .. } => READ_REQ_V2,
+            Request::OpenFileV2 { .. } => OPEN_FILE_REQ_V2,
+            Request::ChecksumV2 { .. } => CHECKSUM_REQ_V2,
+            Request::Sync { .. } => SYNC_REQ,
+            Request::DeleteFile { .. } => DELETE_FILE_REQ,
+            Request::RenameFile { .. } => RENAME_FILE_REQ,
+            Request::GetVersion => GET_VERSION_REQ,
+            Request::SetBaudRate { .. } => SET_BAUD_RATE_REQ,
+            Request::ReadDirAt { .. } => READ_DIR_AT_REQ,
+            Request::MakeDir { .. } => MAKE_DIR_REQ,
+            Request::ChangeDir { .. } => CHANGE_DIR_REQ,
+            Request::GetWorkingDir => GET_WORKING_DIR_REQ,
+            Request::SearchFiles { .. } => SEARCH_FILES_REQ,
+            Request::ExecuteFile { .. } => EXECUTE_FILE_REQ,
+            Request::LockFile { .. } => LOCK_FILE_REQ,
+            Request::UnlockFile { .. } => UNLOCK_FILE_REQ,
+            Request::SetVolumeLabel { .. } => SET_VOLUME_LABEL_REQ,
+            Request::GetVolumeLabel => GET_VOLUME_LABEL_REQ,
+            Request::GetFileAttributes { .. } => GET_FILE_ATTRIBUTES_REQ,
+            Request::SetFileAttributes { .. } => SET_FILE_ATTRIBUTES_REQ,
+            Request::GetMemoryInfo => GET_MEMORY_INFO_REQ,
+            #[cfg(feature = "debug")]
+            Request::DebugDump { .. } => DEBUG_DUMP_REQ,
+            Request::ScreenCapture => SCREEN_CAPTURE_REQ,
+        }
+    }
+
+    /// The opcode of the Confirmation the PC usually sends in reply.
+    ///
+    /// A ReadDir request may also be answered with ReadDirEnd, and a
+    /// SearchFiles request is answered with SearchResult and then
+    /// SearchResultEnd. Neither end opcode follows this rule, so use
+    /// `accepts_confirmation` to check a reply.
+    pub fn expected_confirmation_opcode(&self) -> u8 {
+        self.opcode() | 0x80
+    }
+
+    /// Is a Confirmation with `opcode` a valid reply to this request?
+    pub fn accepts_confirmation(&self, opcode: u8) -> bool {
+        match self {
+            Request::ReadDir { .. } => opcode == READ_DIR_CFM || opcode == READ_DIR_END_CFM,
+            Request::SearchFiles { .. } => {
+                opcode == SEARCH_RESULT_CFM || opcode == SEARCH_RESULT_END_CFM
+            }
+            _ => opcode == self.expected_confirmation_opcode(),
+        }
+    }
+}
+
+impl<'a> Confirmation<'a> {
+    /// The error the PC sent, or `None` if the request was successful.
+    pub fn error(&self) -> Option<Error> {
+        match *self {
+            Confirmation::Ping
+            | Confirmation::PingExt { .. }
+            | Confirmation::ReadDirEnd
+            | Confirmation::SearchResultEnd => None,
+            Confirmation::OpenFile { error, .. }
+            | Confirmation::CloseFile { error }
+            | Confirmation::Read { error, .. }
+            | Confirmation::WriteFile { error }
+            | Confirmation::Checksum { error, .. }
+            | Confirmation::OpenDir { error, .. }
+            | Confirmation::CloseDir { error }
+            | Confirmation::ReadDir { error, .. }
+            | Confirmation::CloseAll { error, .. }
+            | Confirmation::ReadV2 { error, .. }
+            | Confirmation::OpenFileV2 { error, .. }
+            | Confirmation::ChecksumV2 { error, .. }
+            | Confirmation::Sync { error }
+            | Confirmation::DeleteFile { error }
+            | Confirmation::RenameFile { error }
+            | Confirmation::GetVersion { error, .. }
+            | Confirmation::SetBaudRate { error, .. }
+            | Confirmation::ReadDirAt { error, .. }
+            | Confirmation::MakeDir { error }
+            | Confirmation::ChangeDir { error }
+            | Confirmation::GetWorkingDir { error, .. }
+            | Confirmation::SearchResult { error, .. }
+            | Confirmation::ExecuteFile { error, .. }
+            | Confirmation::LockFile { error }
+            | Confirmation::UnlockFile { error }
+            | Confirmation::SetVolumeLabel { error }
+            | Confirmation::GetVolumeLabel { error, .. }
+            | Confirmation::GetFileAttributes { error, .. }
+            | Confirmation::SetFileAttributes { error }
+            | Confirmation::GetMemoryInfo { error, .. }
+            | Confirmation::ScreenCapture { error, .. } => error,
+            #[cfg(feature = "debug")]
+            Confirmation::DebugDump { error, .. } => error,
+        }
+    }
+
+    /// Did the request fail?
+    pub fn is_error(&self) -> bool {
+        self.error().is_some()
+    }
+}
+
+/// Two writers are equal if they hold the same payload, however much of it
+/// each has sent.
+impl PartialEq for CommandWriter {
+    fn eq(&self, other: &CommandWriter) -> bool {
+        self.bytes[0..self.count] == other.bytes[0..other.count]
+    }
+}
+
+impl Eq for CommandWriter {}
+
+impl Default for CommandWriter {
+    fn default() -> CommandWriter {
+        CommandWriter::new()
+    }
+}
+
+impl CommandWriter {
+    /// Make a writer. Call one of the `send_` methods to build a frame
+    /// before calling `get_byte`, which gives nothing until then.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::CommandWriter;
+    /// let mut cw = CommandWriter::new();
+    /// assert_eq!(cw.payload_crc(), None);
+    /// assert_eq!(cw.get_byte(), None);
+    /// cw.send_ping_cfm();
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// assert_eq!(cw.get_byte(), Some(0x81));
+    /// ```
+    pub fn new() -> CommandWriter {
+        CommandWriter {
+            bytes: [0u8; MAX_PAYLOAD],
+            // Past the closing END of an empty frame, as after `reset`
+            sent: MIN_FRAME_OVERHEAD,
+            count: 0,
+            had_escape: false,
+            crc: 0,
+            prepared: false,
+            total_bytes_sent: 0,
+            frames_sent: 0,
+        }
+    }
+
+    /// Throw away the current frame. `get_byte` gives nothing until the
+    /// next one is built.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        // Past the closing END of an empty frame
+        self.sent = MIN_FRAME_OVERHEAD;
+        self.had_escape = false;
+        self.prepared = false;
+    }
+
+    pub fn prep_for_send(&mut self) {
+        self.sent = 0;
+        self.crc = compute_crc_x25(&self.bytes[0..self.count]);
+        self.prepared = true;
+    }
+
+    /// Build a new payload with `f` and prepare it for sending. If `f`
+    /// fails, the writer is reset, so neither a partial payload nor the
+    /// previous frame is sent by mistake.
+    fn build<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut CommandWriter) -> Result<(), Error>,
+    {
+        self.count = 0;
+        let result = f(self);
+        match result {
+            Ok(()) => self.prep_for_send(),
+            Err(_) => self.reset(),
+        }
+        result
+    }
+
+    /// The CRC that ends the current frame, or `None` if no frame has been
+    /// prepared since the last `reset`.
+    pub fn payload_crc(&self) -> Option<u16> {
+        if self.prepared {
+            Some(self.crc)
+        } else {
+            None
+        }
+    }
+
+    /// Check the PC is there. It replies with a Ping Confirmation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::CommandWriter;
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_ping_req();
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// assert_eq!(cw.get_byte(), Some(0x01));
+    /// assert_eq!(cw.get_byte(), Some(0xE1));
+    /// assert_eq!(cw.get_byte(), Some(0xF1));
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// ```
+    pub fn send_ping_req(&mut self) {
+        self.bytes[0] = PING_REQ;
+        self.count = 1;
+        self.prep_for_send();
+    }
+
+    pub fn send_ping_cfm(&mut self) {
+        self.bytes[0] = PING_CFM;
+        self.count = 1;
+        self.prep_for_send();
+    }
+
+    /// Send a Ping carrying `data`, which the PC should echo back.
+    pub fn send_ping_req_ext(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(PING_REQ_EXT)?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_ping_cfm_ext(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(PING_CFM_EXT)?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a `PING_REQ_EXT` payload, as returned by
+    /// `CommandReader::push_byte`, by echoing its data back unchanged.
+    pub fn send_ping_cfm_echo(&mut self, req_payload: &[u8]) -> Result<(), Error> {
+        self.build(|cw| match req_payload.split_first() {
+            Some((&PING_REQ_EXT, data)) => cw.send_ping_cfm_ext(data),
+            _ => Err(Error::BadHeader),
+        })
+    }
+
+    /// Send an already assembled payload (opcode and arguments) as it is.
+    pub fn send_raw_bytes(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_bytes(payload)?;
+            Ok(())
+        })
+    }
+
+    /// A copy of this writer for retransmitting the frame, say if no
+    /// Confirmation arrives in time. The copy starts from the beginning of
+    /// the frame, however much of it this writer has given out.
+    pub fn clone_for_retry(&self) -> CommandWriter {
+        let mut retry = self.clone();
+        retry.sent = 0;
+        retry.had_escape = false;
+        retry
+    }
+
+    /// Send any `Request`, using the specific builder for it.
+    pub fn send_request(&mut self, req: &Request) -> Result<(), Error> {
+        match *req {
+            Request::Ping => {
+                self.send_ping_req();
+                Ok(())
+            }
+            Request::OpenFile { filename, mode } => self.send_open_file_req(filename, mode),
+            Request::CloseFile { handle } => self.send_close_file_req(handle),
+            Request::Read { handle, offset } => self.send_read_req(handle, offset),
+            Request::WriteFile {
+                handle,
+                offset,
+                data,
+            } => self.send_write_file_req(handle, offset, data),
+            Request::Checksum { handle } => self.send_checksum_req(handle),
+            Request::OpenDir => self.send_open_dir_req(),
+            Request::CloseDir { handle } => self.send_close_dir_req(handle),
+            Request::ReadDir { handle } => self.send_read_dir_req(handle),
+            Request::CloseAll => self.send_close_all_req(),
+            Request::PingExt { data } => self.send_ping_req_ext(data),
+            Request::ReadV2 {
+                handle,
+                offset,
+                count,
+            } => self.send_read_req_v2(handle, offset, count),
+            Request::OpenFileV2 {
+                filename,
+                mode,
+                size_hint,
+            } => self.send_open_file_req_v2(filename, mode, size_hint),
+            Request::ChecksumV2 { handle } => self.send_checksum_req_v2(handle),
+            Request::Sync { handle } => self.send_sync_req(handle),
+            Request::DeleteFile { filename } => self.send_delete_file_req(filename),
+            Request::RenameFile { old, new } => self.send_rename_file_req(old, new),
+            Request::GetVersion => self.send_get_version_req(),
+            Request::SetBaudRate { baud } => self.send_set_baud_rate_req(baud),
+            Request::ReadDirAt { handle, index } => self.send_read_dir_at_req(handle, index),
+            Request::MakeDir { dirname } => self.send_make_dir_req(dirname),
+            Request::ChangeDir { dirname } => self.send_change_dir_req(dirname),
+            Request::GetWorkingDir => self.send_get_working_dir_req(),
+            Request::SearchFiles { pattern } => self.send_search_files_req(pattern),
+            Request::ExecuteFile { filename } => self.send_execute_file_req(filename),
+            Request::LockFile { handle } => self.send_lock_file_req(handle),
+            Request::UnlockFile { handle } => self.send_unlock_file_req(handle),
+            Request::SetVolumeLabel { label } => self.send_set_volume_label_req(label),
+            Request::GetVolumeLabel => self.send_get_volume_label_req(),
+            Request::GetFileAttributes { filename } => self.send_get_file_attributes_req(filename),
+            Request::SetFileAttributes { filename, attrs } => {
+                self.send_set_file_attributes_req(filename, attrs)
+            }
+            Request::GetMemoryInfo => self.send_get_memory_info_req(),
+            #[cfg(feature = "debug")]
+            Request::DebugDump { address, length } => self.send_debug_dump_req(address, length),
+            Request::ScreenCapture => self.send_screen_capture_req(),
+        }
+    }
+
+    /// Send any `Confirmation`, using the specific builder for it.
+    pub fn send_confirmation(&mut self, cfm: &Confirmation) -> Result<(), Error> {
+        match *cfm {
+            Confirmation::Ping => {
+                self.send_ping_cfm();
+                Ok(())
+            }
+            Confirmation::OpenFile { handle, error } => self.send_open_cfm(Some(handle), error),
+            Confirmation::CloseFile { error } => self.send_close_cfm(error),
+            Confirmation::Read { data, error } => self.send_read_cfm(data, error),
+            Confirmation::WriteFile { error } => self.send_write_file_cfm(error),
+            Confirmation::Checksum { checksum, error } => self.send_checksum_cfm(checksum, error),
+            Confirmation::OpenDir { handle, error } => self.send_open_dir_cfm(Some(handle), error),
+            Confirmation::CloseDir { error } => self.send_close_dir_cfm(error),
+            Confirmation::ReadDir { ref entry, error } => {
+                self.send_read_dir_cfm(entry.as_ref(), error)
+            }
+            Confirmation::CloseAll {
+                handles_closed,
+                error,
+            } => self.send_close_all_cfm(handles_closed, error),
+            Confirmation::PingExt { data } => self.send_ping_cfm_ext(data),
+            Confirmation::ReadV2 {
+                offset,
+                data,
+                error,
+            } => self.send_read_cfm_with_offset(offset, data, error),
+            Confirmation::OpenFileV2 { handle, error } => {
+                self.send_open_cfm_v2(Some(handle), error)
+            }
+            Confirmation::ChecksumV2 {
+                algorithm,
+                checksum,
+                error,
+            } => self.send_checksum_cfm_v2(algorithm, checksum, error),
+            Confirmation::ReadDirEnd => self.send_read_dir_cfm_end(),
+            Confirmation::Sync { error } => self.send_flush_cfm(error),
+            Confirmation::DeleteFile { error } => self.send_delete_cfm(error),
+            Confirmation::RenameFile { error } => self.send_rename_cfm(error),
+            Confirmation::GetVersion { version, error } => {
+                self.send_get_version_cfm(version, error)
+            }
+            Confirmation::SetBaudRate {
+                accepted_baud,
+                error,
+            } => self.send_set_baud_rate_cfm(accepted_baud, error),
+            Confirmation::ReadDirAt { ref entry, error } => {
+                self.send_read_dir_at_cfm(entry.as_ref(), error)
+            }
+            Confirmation::MakeDir { error } => self.send_make_dir_cfm(error),
+            Confirmation::ChangeDir { error } => self.send_change_dir_cfm(error),
+            Confirmation::GetWorkingDir { path, error } => {
+                self.send_get_working_dir_cfm(path, error)
+            }
+            Confirmation::SearchResult { ref entry, error } => {
+                self.send_search_result_cfm(entry.as_ref(), error)
+            }
+            Confirmation::SearchResultEnd => self.send_search_result_cfm_end(),
+            Confirmation::ExecuteFile { exit_code, error } => {
+                self.send_execute_cfm(exit_code, error)
+            }
+            Confirmation::LockFile { error } => self.send_lock_file_cfm(error),
+            Confirmation::UnlockFile { error } => self.send_unlock_file_cfm(error),
+            Confirmation::SetVolumeLabel { error } => self.send_set_volume_label_cfm(error),
+            Confirmation::GetVolumeLabel { label, error } => {
+                self.send_get_volume_label_cfm(label, error)
+            }
+            Confirmation::GetFileAttributes { attrs, error } => {
+                self.send_get_file_attributes_cfm(attrs, error)
+            }
+            Confirmation::SetFileAttributes { error } => self.send_set_file_attributes_cfm(error),
+            Confirmation::GetMemoryInfo { info, error } => {
+                self.send_get_memory_info_cfm(info, error)
+            }
+            #[cfg(feature = "debug")]
+            Confirmation::DebugDump {
+                address,
+                data,
+                error,
+            } => self.send_debug_dump_cfm(address, data, error),
+            Confirmation::ScreenCapture { chunk, error } => {
+                self.send_screen_capture_cfm(chunk, error)
+            }
+        }
+    }
+
+    /// Ask the PC to open `filename`, which must not contain a null byte.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::{CommandWriter, Mode};
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_open_file_req(b"A.TXT", Mode::Read).unwrap();
+    /// let mut frame = [0u8; 16];
+    /// for b in frame.iter_mut() {
+    ///     *b = cw.get_byte().unwrap_or(0);
+    /// }
+    /// assert_eq!(&frame[0..9], b"\xC0\x02A.TXT\x00\x01");
+    /// ```
+    pub fn send_open_file_req(&mut self, filename: &[u8], mode: Mode) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(OPEN_FILE_REQ)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            cw.encode_u8(mode.wire_byte())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an OpenFile request. A `handle` of `None` is sent as
+    /// `Handle::NULL`.
+    pub fn send_open_cfm(
+        &mut self,
+        handle: Option<Handle>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(OPEN_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(handle.unwrap_or(Handle::NULL).to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an OpenFile request for a file that doesn't exist.
+    pub fn send_open_cfm_not_found(&mut self) -> Result<(), Error> {
+        self.send_open_cfm(None, Some(Error::FileNotFound))
+    }
+
+    /// Like `send_open_file_req`, with a hint of how big the file will get.
+    /// A `size_hint` of 0 means unknown.
+    ///
+    /// A PC must handle both OpenFile and OpenFileV2, so older Monotrons
+    /// which only send OpenFile keep working.
+    pub fn send_open_file_req_v2(
+        &mut self,
+        filename: &[u8],
+        mode: Mode,
+        size_hint: u32,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(OPEN_FILE_REQ_V2)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            cw.encode_u8(mode.wire_byte())?;
+            cw.encode_u32_be(size_hint)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an OpenFileV2 request. A `handle` of `None` is sent as
+    /// `Handle::NULL`.
+    pub fn send_open_cfm_v2(
+        &mut self,
+        handle: Option<Handle>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(OPEN_FILE_CFM_V2)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(handle.unwrap_or(Handle::NULL).to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to close a file. The handle must not be used again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::{CommandWriter, Handle};
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_close_file_req(Handle::new(3)).unwrap();
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// assert_eq!(cw.get_byte(), Some(0x03));
+    /// assert_eq!(cw.get_byte(), Some(0x03));
+    /// ```
+    pub fn send_close_file_req(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_FILE_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    pub fn send_close_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC for the data at `offset` in the file. The PC decides how
+    /// much data to send back.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::{CommandWriter, Handle};
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_read_req(Handle::new(1), 0x1234).unwrap();
+    /// let mut frame = [0u8; 16];
+    /// for b in frame.iter_mut() {
+    ///     *b = cw.get_byte().unwrap_or(0);
+    /// }
+    /// assert_eq!(&frame[0..7], &[0xC0, 0x04, 0x01, 0x00, 0x00, 0x12, 0x34]);
+    /// ```
+    pub fn send_read_req(&mut self, handle: Handle, offset: u32) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            cw.encode_u32_be(offset)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a Read request. Sending less data than will fit in a frame
+    /// tells the Monotron it has reached the end of the file.
+    pub fn send_read_cfm(&mut self, data: &[u8], error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    /// Like `send_read_req`, but the PC sends back at most `count` bytes
+    /// and replies with `READ_CFM_V2`, which carries the offset so an
+    /// out-of-order reply can be spotted. `count` must be at least 1; the
+    /// PC sends no more than fits in the Monotron's receive buffer, however
+    /// big `count` is. A `count` of 0 gives `Error::BadOffset`.
+    pub fn send_read_req_v2(
+        &mut self,
+        handle: Handle,
+        offset: u32,
+        count: u16,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if count == 0 {
+                return Err(Error::BadOffset);
+            }
+            cw.encode_u8(READ_REQ_V2)?;
+            cw.encode_u8(handle.to_wire())?;
+            cw.encode_u32_be(offset)?;
+            cw.encode_u16_be(count)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a ReadV2 request, giving back the `offset` it asked for.
+    pub fn send_read_cfm_with_offset(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_CFM_V2)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u32_be(offset)?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a Read request at or beyond the end of the file.
+    pub fn send_read_cfm_eof(&mut self) -> Result<(), Error> {
+        self.send_read_cfm(&[], None)
+    }
+
+    /// Ask the PC to write `data` at `offset` in the file. At most
+    /// `MAX_WRITE_CHUNK` bytes can be written at once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::{CommandWriter, Handle};
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_write_file_req(Handle::new(1), 16, b"Hi").unwrap();
+    /// let mut frame = [0u8; 16];
+    /// for b in frame.iter_mut() {
+    ///     *b = cw.get_byte().unwrap_or(0);
+    /// }
+    /// assert_eq!(&frame[0..9], b"\xC0\x05\x01\x00\x00\x00\x10Hi");
+    /// ```
+    pub fn send_write_file_req(
+        &mut self,
+        handle: Handle,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(WRITE_FILE_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            cw.encode_u32_be(offset)?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_write_file_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(WRITE_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to make sure everything written to the file is on disk.
+    pub fn send_sync_req(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SYNC_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a Sync request. Use `Error::IoError` if the disk failed.
+    pub fn send_flush_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SYNC_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC for an advisory lock on the file. Other handles can still
+    /// read and write it, but can't lock it until it is unlocked or closed.
+    pub fn send_lock_file_req(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(LOCK_FILE_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a LockFile request. Use `Error::Locked` if another handle
+    /// holds the lock.
+    pub fn send_lock_file_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(LOCK_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    pub fn send_unlock_file_req(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(UNLOCK_FILE_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an UnlockFile request. Use `Error::InvalidHandle` if the
+    /// handle doesn't hold a lock.
+    pub fn send_unlock_file_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(UNLOCK_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to delete `filename`, which must not contain a null byte.
+    pub fn send_delete_file_req(&mut self, filename: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(DELETE_FILE_REQ)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_delete_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(DELETE_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a DeleteFile request for a file that doesn't exist.
+    pub fn send_delete_cfm_not_found(&mut self) -> Result<(), Error> {
+        self.send_delete_cfm(Some(Error::FileNotFound))
+    }
+
+    /// Ask the PC to rename the file `old` to `new`. Neither name may
+    /// contain a null byte.
+    pub fn send_rename_file_req(&mut self, old: &[u8], new: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if old.contains(&0) || new.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(RENAME_FILE_REQ)?;
+            cw.encode_bytes(old)?;
+            cw.encode_u8(0)?;
+            cw.encode_bytes(new)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_rename_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(RENAME_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to make a directory called `dirname`, which must not
+    /// contain a null byte.
+    pub fn send_make_dir_req(&mut self, dirname: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if dirname.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(MAKE_DIR_REQ)?;
+            cw.encode_bytes(dirname)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_make_dir_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(MAKE_DIR_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to change the current directory to `dirname`, which must
+    /// not contain a null byte.
+    pub fn send_change_dir_req(&mut self, dirname: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if dirname.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(CHANGE_DIR_REQ)?;
+            cw.encode_bytes(dirname)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_change_dir_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CHANGE_DIR_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    pub fn send_get_working_dir_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_WORKING_DIR_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a GetWorkingDir request. `path` must not contain a null
+    /// byte, and gives `Error::BufferOverflow` if it is longer than
+    /// `MAX_PATH_LEN`. Send an empty `path` with an `error`.
+    pub fn send_get_working_dir_cfm(
+        &mut self,
+        path: &[u8],
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if path.len() > MAX_PATH_LEN {
+                return Err(Error::BufferOverflow);
+            }
+            if path.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(GET_WORKING_DIR_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_bytes(path)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to change the volume label to `label`, which must not
+    /// contain a null byte, and gives `Error::BufferOverflow` if it is longer
+    /// than `MAX_VOLUME_LABEL_LEN`.
+    pub fn send_set_volume_label_req(&mut self, label: &[u8]) -> Result<(), Error> {
+        self.send_volume_label(SET_VOLUME_LABEL_REQ, &[], label)
+    }
+
+    pub fn send_set_volume_label_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SET_VOLUME_LABEL_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    pub fn send_get_volume_label_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_VOLUME_LABEL_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a GetVolumeLabel request. `label` has the same limits as in
+    /// `send_set_volume_label_req`, and may be empty if the disk has no
+    /// label. Send an empty `label` with an `error`.
+    pub fn send_get_volume_label_cfm(
+        &mut self,
+        label: &[u8],
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.send_volume_label(GET_VOLUME_LABEL_CFM, &[error_to_wire(error)], label)
+    }
+
+    /// Send `opcode`, then `header`, then the null-terminated `label`.
+    fn send_volume_label(&mut self, opcode: u8, header: &[u8], label: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if label.len() > MAX_VOLUME_LABEL_LEN {
+                return Err(Error::BufferOverflow);
+            }
+            if label.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(opcode)?;
+            cw.encode_bytes(header)?;
+            cw.encode_bytes(label)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC for the entries in the current directory matching
+    /// `pattern`, which must not contain a null byte. Keep reading
+    /// Confirmations until SearchResultEnd arrives.
+    pub fn send_search_files_req(&mut self, pattern: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if pattern.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(SEARCH_FILES_REQ)?;
+            cw.encode_bytes(pattern)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    /// Send one of the entries matching a SearchFiles request. An `entry` of
+    /// `None` with no `error` sends SearchResultEnd instead. If there is an
+    /// `error`, no entry is sent.
+    pub fn send_search_result_cfm(
+        &mut self,
+        entry: Option<&DirEntry>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SEARCH_RESULT_CFM)?;
+            match (entry, error) {
+                (Some(entry), None) => cw.encode_dir_entry(entry)?,
+                (_, Some(error)) => cw.encode_u8(error.to_wire())?,
+                (None, None) => return cw.send_search_result_cfm_end(),
+            }
+            Ok(())
+        })
+    }
+
+    /// Reply to a SearchFiles request when there are no more matching
+    /// entries.
+    pub fn send_search_result_cfm_end(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SEARCH_RESULT_END_CFM)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC for the attributes of `filename`, which must not contain a
+    /// null byte.
+    pub fn send_get_file_attributes_req(&mut self, filename: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(GET_FILE_ATTRIBUTES_REQ)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a GetFileAttributes request. Send `FileAttributes::empty()`
+    /// with an `error`.
+    pub fn send_get_file_attributes_cfm(
+        &mut self,
+        attrs: FileAttributes,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_FILE_ATTRIBUTES_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(attrs.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to replace the attributes of `filename`, which must not
+    /// contain a null byte, with `attrs`. To change one bit, read the
+    /// attributes with GetFileAttributes first.
+    pub fn send_set_file_attributes_req(
+        &mut self,
+        filename: &[u8],
+        attrs: FileAttributes,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(SET_FILE_ATTRIBUTES_REQ)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            cw.encode_u8(attrs.to_wire())?;
+            Ok(())
+        })
+    }
+
+    pub fn send_set_file_attributes_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SET_FILE_ATTRIBUTES_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    pub fn send_get_memory_info_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_MEMORY_INFO_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a GetMemoryInfo request. Gives `Error::BadHeader` if `info`
+    /// has more free RAM or stack in use than there is RAM. Send
+    /// `MemoryInfo::default()` with an `error`.
+    pub fn send_get_memory_info_cfm(
+        &mut self,
+        info: MemoryInfo,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if !info.is_valid() {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(GET_MEMORY_INFO_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u32_be(info.total_ram)?;
+            cw.encode_u32_be(info.free_ram)?;
+            cw.encode_u32_be(info.stack_usage)?;
+            Ok(())
+        })
+    }
+
+    /// Ask for a dump of `length` bytes of memory, starting at `address`.
+    /// Keep reading Confirmations until one arrives with no data.
+    #[cfg(feature = "debug")]
+    pub fn send_debug_dump_req(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(DEBUG_DUMP_REQ)?;
+            cw.encode_u32_be(address)?;
+            cw.encode_u32_be(length)?;
+            Ok(())
+        })
+    }
+
+    /// Send one chunk of a memory dump, which is the memory at `address`.
+    /// `data` can be at most `MAX_DEBUG_DUMP_CHUNK` bytes. Send an empty
+    /// `data` to end the dump.
+    #[cfg(feature = "debug")]
+    pub fn send_debug_dump_cfm(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(DEBUG_DUMP_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u32_be(address)?;
+            cw.encode_bytes(data)?;
+            Ok(())
+        })
+    }
+
+    /// Ask for a copy of the screen. Keep reading Confirmations until one
+    /// arrives with an empty chunk.
+    pub fn send_screen_capture_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SCREEN_CAPTURE_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Send the next chunk of the screen, which gives `Error::BufferOverflow`
+    /// if it is longer than `MAX_SCREEN_CAPTURE_CHUNK`. Send an empty `chunk`
+    /// to end the capture.
+    pub fn send_screen_capture_cfm(
+        &mut self,
+        chunk: &[u8],
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            if chunk.len() > MAX_SCREEN_CAPTURE_CHUNK {
+                return Err(Error::BufferOverflow);
+            }
+            cw.encode_u8(SCREEN_CAPTURE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_bytes(chunk)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to run the program in `filename`, which must not contain a
+    /// null byte.
+    pub fn send_execute_file_req(&mut self, filename: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if filename.contains(&0) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(EXECUTE_FILE_REQ)?;
+            cw.encode_bytes(filename)?;
+            cw.encode_u8(0)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an ExecuteFile request with the program's `exit_code`. If
+    /// there is an `error`, such as `Error::ExecFormat`, the program never
+    /// ran and 0 is sent instead of `exit_code`.
+    pub fn send_execute_cfm(&mut self, exit_code: i8, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(EXECUTE_FILE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(if error.is_some() { 0 } else { exit_code as u8 })?;
+            Ok(())
+        })
+    }
+
+    pub fn send_get_version_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_VERSION_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a GetVersion request with the protocol `version` the PC
+    /// speaks, usually `ProtocolVersion::CURRENT`.
+    pub fn send_get_version_cfm(
+        &mut self,
+        version: ProtocolVersion,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(GET_VERSION_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(version.major)?;
+            cw.encode_u8(version.minor)?;
+            cw.encode_u8(version.patch)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to change the speed of the serial link to `baud`. Keep
+    /// using the old speed until the Confirmation arrives, then switch to
+    /// the rate it gives.
+    pub fn send_set_baud_rate_req(&mut self, baud: u32) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SET_BAUD_RATE_REQ)?;
+            cw.encode_u32_be(baud)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a SetBaudRate request, before switching speed.
+    /// `accepted_baud` may differ from the rate asked for, if the PC rounded
+    /// it to a rate it supports.
+    pub fn send_set_baud_rate_cfm(
+        &mut self,
+        accepted_baud: u32,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(SET_BAUD_RATE_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u32_be(accepted_baud)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to checksum the whole of an open file. The PC uses
+    /// `FILE_CHECKSUM`, which is `ChecksumAlgorithm::Crc32Iso`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::{CommandWriter, Handle};
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_checksum_req(Handle::new(2)).unwrap();
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// assert_eq!(cw.get_byte(), Some(0x07));
+    /// assert_eq!(cw.get_byte(), Some(0x02));
+    /// ```
+    pub fn send_checksum_req(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CHECKSUM_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a Checksum request with the `FILE_CHECKSUM` of the whole file.
+    pub fn send_checksum_cfm(&mut self, checksum: u32, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CHECKSUM_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u32_be(checksum)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to checksum the whole of an open file, with whichever
+    /// `ChecksumAlgorithm` it likes.
+    pub fn send_checksum_req_v2(&mut self, handle: Handle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CHECKSUM_REQ_V2)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a ChecksumV2 request, saying which `algorithm` was used.
+    pub fn send_checksum_cfm_v2(
+        &mut self,
+        algorithm: ChecksumAlgorithm,
+        checksum: u32,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CHECKSUM_CFM_V2)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(algorithm.to_wire())?;
+            cw.encode_u32_be(checksum)?;
+            Ok(())
+        })
+    }
+
+    pub fn send_open_dir_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(OPEN_DIR_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to an OpenDir request. A `handle` of `None` is sent as
+    /// `DirHandle::NULL`.
+    pub fn send_open_dir_cfm(
+        &mut self,
+        handle: Option<DirHandle>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(OPEN_DIR_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(handle.unwrap_or(DirHandle::NULL).to_wire())?;
+            Ok(())
+        })
+    }
+
+    pub fn send_close_dir_req(&mut self, handle: DirHandle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_DIR_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    pub fn send_close_dir_cfm(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_DIR_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            Ok(())
+        })
+    }
+
+    pub fn send_read_dir_req(&mut self, handle: DirHandle) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_DIR_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a ReadDir request. An `entry` of `None` marks the end of the
+    /// directory, and is sent as `Error::FileNotFound` unless some other
+    /// `error` is given. If there is an `error`, no entry is sent.
+    pub fn send_read_dir_cfm(
+        &mut self,
+        entry: Option<&DirEntry>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_DIR_CFM)?;
+            match (entry, error) {
+                (Some(entry), None) => cw.encode_dir_entry(entry)?,
+                (_, Some(error)) => cw.encode_u8(error.to_wire())?,
+                (None, None) => cw.encode_u8(Error::FileNotFound.to_wire())?,
+            }
+            Ok(())
+        })
+    }
+
+    /// Ask the PC for entry `index` of the directory, counting from 0. The
+    /// PC replies with `Error::BadOffset` if the directory has no such entry.
+    pub fn send_read_dir_at_req(&mut self, handle: DirHandle, index: u16) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_DIR_AT_REQ)?;
+            cw.encode_u8(handle.to_wire())?;
+            cw.encode_u16_be(index)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a ReadDirAt request. An `entry` of `None` means the index
+    /// was out of range, and is sent as `Error::BadOffset` unless some other
+    /// `error` is given. If there is an `error`, no entry is sent.
+    pub fn send_read_dir_at_cfm(
+        &mut self,
+        entry: Option<&DirEntry>,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_DIR_AT_CFM)?;
+            match (entry, error) {
+                (Some(entry), None) => cw.encode_dir_entry(entry)?,
+                (_, Some(error)) => cw.encode_u8(error.to_wire())?,
+                (None, None) => cw.encode_u8(Error::BadOffset.to_wire())?,
+            }
+            Ok(())
+        })
+    }
+
+    /// Append a success byte then `entry`, in the form `parse_dir_entry`
+    /// expects.
+    fn encode_dir_entry(&mut self, entry: &DirEntry) -> Result<(), Error> {
+        if entry.name.contains(&0) {
+            return Err(Error::BadHeader);
+        }
+        self.encode_u8(error_to_wire(None))?;
+        self.encode_bytes(entry.name)?;
+        self.encode_u8(0)?;
+        self.encode_u32_be(entry.size)?;
+        self.encode_bytes(&entry.mtime.to_wire())?;
+        self.encode_u8(entry.file_type.to_wire())
+    }
+
+    pub fn send_keypress_ind(&mut self, utf8_byte: u8) -> Result<(), Error> {
+        self.send_indication(KEYPRESS_IND, &[utf8_byte])
+    }
+
+    /// Send as many of `utf8_bytes` as will fit (`MAX_KEYPRESSES_PER_FRAME`)
+    /// in one frame, returning how many were used. Send the rest in later
+    /// frames. Nothing is sent if `utf8_bytes` is empty.
+    pub fn send_keypress_batch(&mut self, utf8_bytes: &[u8]) -> Result<usize, Error> {
+        let used = utf8_bytes.len().min(MAX_KEYPRESSES_PER_FRAME);
+        if used == 0 {
+            return Ok(0);
+        }
+        self.send_indication(KEYPRESS_BATCH_IND, &utf8_bytes[0..used])?;
+        Ok(used)
+    }
+
+    /// Tell the Monotron that audio playback has finished, and why.
+    pub fn send_audio_stop_indication(&mut self, reason: AudioStopReason) -> Result<(), Error> {
+        self.send_indication(AUDIO_STOP_IND, &reason.to_wire())
+    }
+
+    /// Tell the Monotron something has happened to the system. Nothing
+    /// waits for this, so the Monotron must handle it whenever it arrives,
+    /// even while waiting for a Confirmation, and carry on waiting
+    /// afterwards.
+    pub fn send_interrupt_indication(&mut self, code: InterruptCode) -> Result<(), Error> {
+        self.send_indication(INTERRUPT_IND, &[code.to_wire()])
+    }
+
+    /// Tell the Monotron the state of the battery. Gives `Error::BadHeader`
+    /// if `ind` isn't valid.
+    pub fn send_battery_indication(&mut self, ind: BatteryIndication) -> Result<(), Error> {
+        self.build(|cw| {
+            if !ind.is_valid() {
+                return Err(Error::BadHeader);
+            }
+            let [mv_hi, mv_lo] = ind.millivolts.to_be_bytes();
+            let flags = if ind.charging {
+                BatteryIndication::FLAG_CHARGING
+            } else {
+                0
+            };
+            cw.send_indication(BATTERY_IND, &[ind.percent, mv_hi, mv_lo, flags])
+        })
+    }
+
+    /// Tell the Monotron the state of the joystick.
+    pub fn send_joystick_indication(&mut self, state: JoystickState) -> Result<(), Error> {
+        self.send_indication(JOYSTICK_IND, &[state.to_wire()])
+    }
+
+    /// Tell the Monotron the screen is now `cols` by `rows` characters.
+    /// Gives `Error::BadHeader` if either is 0.
+    pub fn send_screen_resize_indication(&mut self, cols: u8, rows: u8) -> Result<(), Error> {
+        self.build(|cw| {
+            let ind = ScreenResizeIndication::new(cols, rows)?;
+            cw.send_indication(SCREEN_RESIZE_IND, &[ind.cols, ind.rows])
+        })
+    }
+
+    /// Tell the Monotron the mouse has moved. Gives `Error::BadHeader` if
+    /// either delta is outside -128 to 127.
+    pub fn send_mouse_indication(&mut self, ind: MouseIndication) -> Result<(), Error> {
+        self.build(|cw| {
+            if !(-128..=127).contains(&ind.delta_x) || !(-128..=127).contains(&ind.delta_y) {
+                return Err(Error::BadHeader);
+            }
+            cw.send_indication(
+                MOUSE_IND,
+                &[
+                    ind.delta_x as i8 as u8,
+                    ind.delta_y as i8 as u8,
+                    ind.buttons.to_wire(),
+                ],
+            )
+        })
+    }
+
+    /// Send any indication. All the `send_*_ind` methods are built on this.
+    pub(crate) fn send_indication(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        self.build(|cw| {
+            if !is_indication_opcode(opcode) {
+                return Err(Error::BadHeader);
+            }
+            cw.encode_u8(opcode)?;
+            cw.encode_bytes(payload)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a ReadDir request when there are no more entries.
+    pub fn send_read_dir_cfm_end(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(READ_DIR_END_CFM)?;
+            Ok(())
+        })
+    }
+
+    /// Ask the PC to close every open file and directory, for example
+    /// after the Monotron restarts. Afterwards, every `Handle` and
+    /// `DirHandle` given out so far is as good as `NULL`.
+    pub fn send_close_all_req(&mut self) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_ALL_REQ)?;
+            Ok(())
+        })
+    }
+
+    /// Reply to a CloseAll request, saying how many handles were open.
+    pub fn send_close_all_cfm(
+        &mut self,
+        handles_closed: u8,
+        error: Option<Error>,
+    ) -> Result<(), Error> {
+        self.build(|cw| {
+            cw.encode_u8(CLOSE_ALL_CFM)?;
+            cw.encode_u8(error_to_wire(error))?;
+            cw.encode_u8(handles_closed)?;
+            Ok(())
+        })
+    }
+
+    /// Put a sequence number in front of the frame that has just been
+    /// built, for tracing the protocol while debugging. The sequence number
+    /// goes before the opcode, so only a peer expecting sequence numbers on
+    /// every frame can decode it. Mixing sequenced and plain frames in one
+    /// session gives undefined results.
+    #[cfg(debug_assertions)]
+    pub fn send_with_seq(&mut self, seq: u8) -> Result<(), Error> {
+        if self.count == self.bytes.len() {
+            self.reset();
+            return Err(Error::BufferOverflow);
+        }
+        self.bytes.copy_within(0..self.count, 1);
+        self.bytes[0] = seq;
+        self.count += 1;
+        self.prep_for_send();
+        Ok(())
+    }
+
+    /// Check that the payload built so far decodes as a well-formed
+    /// message, with every field in range. Indications this crate doesn't
+    /// know about are rejected, as are frames with a sequence number.
+    #[cfg(debug_assertions)]
+    pub fn validate_payload(&self) -> Result<(), Error> {
+        Message::parse(&self.bytes[0..self.count])
+            .map(|_| ())
+            .map_err(|_| Error::BadHeader)
+    }
+
+    /// Append a byte to the payload.
+    fn encode_u8(&mut self, value: u8) -> Result<(), Error> {
+        self.encode_bytes(&[value])
+    }
+
+    /// Append a big-endian `u16` to the payload.
+    fn encode_u16_be(&mut self, value: u16) -> Result<(), Error> {
+        self.encode_bytes(&value.to_be_bytes())
+    }
+
+    /// Append a big-endian `u32` to the payload.
+    fn encode_u32_be(&mut self, value: u32) -> Result<(), Error> {
+        self.encode_bytes(&value.to_be_bytes())
+    }
+
+    /// Append `data` to the payload, or none of it if it doesn't fit.
+    fn encode_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.count + data.len();
+        if end > self.bytes.len() {
+            return Err(Error::BufferOverflow);
+        }
+        self.bytes[self.count..end].copy_from_slice(data);
+        self.count = end;
+        Ok(())
+    }
+
+    fn escape_and_send(&mut self, to_send: u8) -> u8 {
+        if !need_escape(to_send) {
+            self.sent += 1;
+            to_send
+        } else if !self.had_escape {
+            self.had_escape = true;
+            ESC
+        } else {
+            self.sent += 1;
+            self.had_escape = false;
+            escape(to_send)
+        }
+    }
+
+    /// The number of bytes given out by `get_byte`, including framing and
+    /// escape bytes.
+    pub fn total_bytes_sent(&self) -> u64 {
+        self.total_bytes_sent
+    }
+
+    /// The number of frames completely given out by `get_byte`.
+    pub fn frames_sent(&self) -> u32 {
+        self.frames_sent
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.total_bytes_sent = 0;
+        self.frames_sent = 0;
+    }
+
+    /// The next byte of the frame to put on the wire, or `None` once the
+    /// whole frame has been given out. `END` and `ESC` bytes in the payload
+    /// come out escaped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::CommandWriter;
+    /// let mut cw = CommandWriter::new();
+    /// cw.send_read_cfm(&[0xC0], None).unwrap();
+    /// assert_eq!(cw.get_byte(), Some(0xC0));
+    /// assert_eq!(cw.get_byte(), Some(0x84));
+    /// assert_eq!(cw.get_byte(), Some(0x00));
+    /// assert_eq!(cw.get_byte(), Some(0xDB));
+    /// assert_eq!(cw.get_byte(), Some(0xDC));
+    /// ```
+    pub fn get_byte(&mut self) -> Option<u8> {
+        let result = self.next_byte();
+        if result.is_some() {
+            self.total_bytes_sent = self.total_bytes_sent.wrapping_add(1);
+            if self.sent == self.count + 4 {
+                self.frames_sent = self.frames_sent.wrapping_add(1);
+            }
+        }
+        result
+    }
+
+    /// Take all the bytes of the frame, so they can be sent from somewhere
+    /// else. Fails if they don't fit in `M` bytes.
+    #[cfg(feature = "heapless")]
+    pub fn into_framed_vec<const M: usize>(mut self) -> Result<heapless::Vec<u8, M>, Error> {
+        let mut vec = heapless::Vec::new();
+        while let Some(b) = self.get_byte() {
+            vec.push(b).map_err(|_| Error::BufferOverflow)?;
+        }
+        Ok(vec)
+    }
+
+    /// Send all the bytes of the frame to `sink`, returning how many were
+    /// written.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: std::io::Write>(&mut self, sink: &mut W) -> std::io::Result<usize> {
+        let mut buf = [0u8; 16];
+        let mut total = 0;
+        loop {
+            let mut len = 0;
+            while len < buf.len() {
+                match self.get_byte() {
+                    Some(b) => {
+                        buf[len] = b;
+                        len += 1;
+                    }
+                    None => break,
+                }
+            }
+            if len == 0 {
+                return Ok(total);
+            }
+            sink.write_all(&buf[0..len])?;
+            total += len;
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.sent == 0 {
+            self.sent += 1;
+            Some(END)
+        } else if self.sent <= self.count {
+            let to_send = self.bytes[self.sent - 1];
+            Some(self.escape_and_send(to_send))
+        } else if self.sent == (self.count + 1) {
+            let to_send = (self.crc >> 8) as u8;
+            Some(self.escape_and_send(to_send))
+        } else if self.sent == (self.count + 2) {
+            let to_send = self.crc as u8;
+            Some(self.escape_and_send(to_send))
+        } else if self.sent == (self.count + 3) {
+            self.sent += 1;
+            Some(END)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> WriteAllSession<'a> {
+    /// Write `data` at the start of the file, `chunk_size` bytes at a time.
+    /// The chunk size is limited to between 1 and `MAX_WRITE_CHUNK`.
+    pub fn new(handle: Handle, data: &'a [u8], chunk_size: usize) -> WriteAllSession<'a> {
+        WriteAllSession {
+            handle,
+            data,
+            offset: 0,
+            chunk_size: chunk_size.clamp(1, MAX_WRITE_CHUNK),
+        }
+    }
+
+    /// Build the request for the next unwritten chunk. Gives `None` once
+    /// everything has been written.
+    pub fn next_request(&mut self, writer: &mut CommandWriter) -> Option<Result<(), Error>> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        let offset = match u32::try_from(self.offset) {
+            Ok(offset) => offset,
+            Err(_) => return Some(Err(Error::BadOffset)),
+        };
+        Some(writer.send_write_file_req(self.handle, offset, self.chunk()))
+    }
+
+    /// Process the reply to the last request. Gives `true` when everything
+    /// has been written.
+    pub fn handle_confirmation(&mut self, cfm: &Confirmation) -> Result<bool, Error> {
+        match *cfm {
+            Confirmation::WriteFile { error: None } => {
+                self.offset += self.chunk().len();
+                Ok(self.offset >= self.data.len())
+            }
+            Confirmation::WriteFile { error: Some(e) } => Err(e),
+            _ => Err(Error::BadHeader),
+        }
+    }
+
+    fn chunk(&self) -> &'a [u8] {
+        let end = self.data.len().min(self.offset + self.chunk_size);
+        &self.data[self.offset..end]
+    }
+}
+
+impl Default for CommandReader {
+    fn default() -> CommandReader {
+        CommandReader::new()
+    }
+}
+
+impl CommandReader {
+    pub fn new() -> CommandReader {
+        CommandReader {
+            bytes: [0u8; MAX_PAYLOAD + CRC16_BYTES],
+            count: 0,
+            state: ReadState::WaitingForStart,
+            bytes_consumed: 0,
+            frames_received: 0,
+            checksum_errors: 0,
+            consecutive_bad_checksums: 0,
+            overflow_occurred: false,
+            frame_ready: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.state = ReadState::WaitingForStart;
+        self.overflow_occurred = false;
+        self.frame_ready = false;
+    }
+
+    /// The largest payload (opcode and arguments) this reader can receive.
+    /// Longer frames give `Error::BufferOverflow`.
+    pub fn max_payload_capacity(&self) -> usize {
+        self.bytes.len() - CRC16_BYTES
+    }
+
+    /// Has a frame been dropped because it was too long, since the reader
+    /// was created or last `reset`?
+    pub fn overflow_occurred(&self) -> bool {
+        self.overflow_occurred
+    }
+
+    /// Process a byte received from the serial port. Returns the payload
+    /// (opcode and arguments, without the CRC) once a whole frame has been
+    /// received and its CRC checked. Bytes received before the start of a
+    /// frame are discarded. After an error, the reader waits for the next
+    /// frame.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use monotron_interface::CommandReader;
+    /// let mut cr = CommandReader::new();
+    /// for &b in &[0xC0, 0x01, 0xE1, 0xF1] {
+    ///     assert_eq!(cr.push_byte(b), Ok(None));
+    /// }
+    /// assert_eq!(cr.push_byte(0xC0), Ok(Some(&[0x01][..])));
+    /// ```
+    pub fn push_byte(&mut self, byte: u8) -> Result<Option<&[u8]>, Error> {
+        if self.process_byte(byte)? {
+            Ok(Some(&self.bytes[0..self.count - CRC16_BYTES]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read bytes from `src` one at a time until a whole frame has been
+    /// received, so no bytes after the frame are taken from `src`. Corrupt
+    /// frames are skipped. Returns `Ok(None)` if `src` would block or has no
+    /// more data; a partly received frame is kept for the next call.
+    #[cfg(feature = "std")]
+    pub fn read_from<R: std::io::Read>(&mut self, src: &mut R) -> std::io::Result<Option<&[u8]>> {
+        let mut byte = [0u8; 1];
+        loop {
+            match src.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {
+                    if let Ok(true) = self.process_byte(byte[0]) {
+                        return Ok(Some(&self.bytes[0..self.count - CRC16_BYTES]));
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Is the reader between frames? If not, it is part way through a frame
+    /// (which may turn out to be corrupt).
+    pub fn is_synchronised(&self) -> bool {
+        self.state == ReadState::WaitingForStart
+    }
+
+    /// Drop any partly received frame and wait for the start of the next
+    /// one, as happens after an error. Use this if the UART reports a
+    /// framing error.
+    pub fn force_resync(&mut self) {
+        self.count = 0;
+        self.state = ReadState::WaitingForStart;
+        self.frame_ready = false;
+    }
+
+    /// How long the frame being received will be, including the `END` bytes
+    /// and CRC, once its opcode has arrived. Escaping makes a frame longer
+    /// than this. Gives `None` between frames and for opcodes whose frames
+    /// vary in length.
+    pub fn frame_length_hint(&self) -> Option<usize> {
+        if self.state == ReadState::WaitingForStart || self.count == 0 {
+            return None;
+        }
+        fixed_payload_len(self.bytes[0]).map(|len| len + MIN_FRAME_OVERHEAD)
+    }
+
+    /// Take the frame completed by the last byte given to `push_byte`,
+    /// checking it has the `expected` opcode. Each frame can only be taken
+    /// once.
+    pub fn assert_opcode(&mut self, expected: u8) -> Result<&[u8], ProtocolError> {
+        if !self.frame_ready {
+            return Err(ProtocolError::NoFrame);
+        }
+        self.frame_ready = false;
+        let payload = &self.bytes[0..self.count - CRC16_BYTES];
+        match payload.first() {
+            Some(&opcode) if opcode == expected => Ok(payload),
+            Some(&opcode) => Err(ProtocolError::UnknownOpcode(opcode)),
+            None => Err(ProtocolError::NoFrame),
+        }
+    }
+
+    /// The number of bytes given to `push_byte` or `skip_to_next_frame`.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.bytes_consumed
+    }
+
+    /// The number of valid frames received.
+    pub fn frames_received(&self) -> u32 {
+        self.frames_received
+    }
+
+    /// The number of frames dropped because their CRC was wrong.
+    pub fn checksum_errors(&self) -> u32 {
+        self.checksum_errors
+    }
+
+    /// The number of frames dropped because their CRC was wrong, since the
+    /// last valid frame. A large number suggests the baud rate is wrong.
+    pub fn consecutive_bad_checksums(&self) -> u32 {
+        self.consecutive_bad_checksums
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.bytes_consumed = 0;
+        self.frames_received = 0;
+        self.checksum_errors = 0;
+        self.consecutive_bad_checksums = 0;
+    }
+
+    /// Throw away `bytes` up to and including the first `END`, and get ready
+    /// for the frame that follows it. Returns how many bytes were consumed,
+    /// which is all of them if there is no `END`. Any partly received frame
+    /// is dropped.
+    pub fn skip_to_next_frame(&mut self, bytes: &[u8]) -> usize {
+        let consumed = match bytes.iter().position(|&b| b == END) {
+            Some(idx) => {
+                self.count = 0;
+                self.state = ReadState::InFrame;
+                self.frame_ready = false;
+                idx + 1
+            }
+            None => {
+                self.force_resync();
+                bytes.len()
+            }
+        };
+        self.bytes_consumed = self.bytes_consumed.wrapping_add(consumed as u64);
+        consumed
+    }
+
+    /// Process a byte and keep the counters up to date.
+    fn process_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        self.bytes_consumed = self.bytes_consumed.wrapping_add(1);
+        self.frame_ready = false;
+        let result = self.decode_byte(byte);
+        match result {
+            Ok(true) => {
+                self.frame_ready = true;
+                self.frames_received = self.frames_received.wrapping_add(1);
+                self.consecutive_bad_checksums = 0;
+            }
+            Err(Error::BadChecksum) => {
+                self.checksum_errors = self.checksum_errors.wrapping_add(1);
+                self.consecutive_bad_checksums = self.consecutive_bad_checksums.wrapping_add(1);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Returns true when `byte` completed a valid frame.
+    fn decode_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        match (self.state, byte) {
+            (ReadState::WaitingForStart, END) => {
+                self.count = 0;
+                self.state = ReadState::InFrame;
+                Ok(false)
+            }
+            (ReadState::WaitingForStart, _) => Ok(false),
+            (ReadState::InFrame, END) if self.count == 0 => Ok(false),
+            (ReadState::InFrame, END) => {
+                self.state = ReadState::WaitingForStart;
+                self.check_frame().map(|_| true)
+            }
+            (ReadState::InFrame, ESC) => {
+                self.state = ReadState::InEscape;
+                Ok(false)
+            }
+            (ReadState::InFrame, _) => self.store(byte).map(|_| false),
+            (ReadState::InEscape, ESC_END) => {
+                self.state = ReadState::InFrame;
+                self.store(END).map(|_| false)
+            }
+            (ReadState::InEscape, ESC_ESC) => {
+                self.state = ReadState::InFrame;
+                self.store(ESC).map(|_| false)
+            }
+            (ReadState::InEscape, END) => {
+                // Treat this as the start of a new frame
+                self.count = 0;
+                self.state = ReadState::InFrame;
+                Err(Error::BadHeader)
+            }
+            (ReadState::InEscape, _) => {
+                self.state = ReadState::WaitingForStart;
+                Err(Error::BadHeader)
+            }
+        }
+    }
+
+    fn store(&mut self, byte: u8) -> Result<(), Error> {
+        if self.count == self.bytes.len() {
+            self.state = ReadState::WaitingForStart;
+            self.overflow_occurred = true;
+            return Err(Error::BufferOverflow);
+        }
+        self.bytes[self.count] = byte;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn check_frame(&self) -> Result<(), Error> {
+        // We need at least an opcode and the CRC
+        if self.count < 1 + CRC16_BYTES {
+            return Err(Error::BadHeader);
+        }
+        let payload_len = self.count - CRC16_BYTES;
+        let (expected, _) = parse_u16_be(&self.bytes[0..self.count], payload_len)?;
+        if compute_crc_x25(&self.bytes[0..payload_len]) == expected {
+            Ok(())
+        } else {
+            Err(Error::BadChecksum)
+        }
+    }
+}
+
+/// Decode an OpenFile or OpenFileV2 confirmation payload into the new file
+/// handle.
+pub fn parse_open_cfm(payload: &[u8]) -> Result<Handle, Error> {
+    match payload {
+        [OPEN_FILE_CFM, error, handle] | [OPEN_FILE_CFM_V2, error, handle] => {
+            check_error(*error)?;
+            Ok(Handle::from_wire(*handle))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// The CRC sent at the end of every frame, calculated over the opcode and
+/// payload. See <https://crccalc.com/>, marked CRC-16/X25.
+pub fn compute_crc_x25(data: &[u8]) -> u16 {
+    crc::crc16::checksum_x25(data)
+}
+
+/// Check the two CRC bytes from the end of a frame match `data`.
+pub fn verify_crc_x25(data: &[u8], crc_hi: u8, crc_lo: u8) -> bool {
+    compute_crc_x25(data) == u16::from_be_bytes([crc_hi, crc_lo])
+}
+
+/// Classify an opcode using the ranges described in `opcodes`.
+pub fn opcode_direction(opcode: u8) -> OpcodeDirection {
+    match opcode {
+        0x01..=0x6F => OpcodeDirection::Request,
+        0x81..=0xEF => OpcodeDirection::Confirmation,
+        0xF0..=0xFF => OpcodeDirection::Indication,
+        _ => OpcodeDirection::Unknown,
+    }
+}
+
+/// Is this byte in the range of opcodes reserved for Indications? Frames
+/// with these opcodes are never a reply to the outstanding Request.
+pub fn is_indication_opcode(b: u8) -> bool {
+    opcode_direction(b) == OpcodeDirection::Indication
+}
+
+/// Decode a CloseFile confirmation payload.
+pub fn parse_close_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [CLOSE_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a CloseDir confirmation payload.
+pub fn parse_close_dir_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [CLOSE_DIR_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Read confirmation payload into the data read. Empty data with
+/// no error means the read was at or beyond the end of the file.
+pub fn parse_read_cfm(payload: &[u8]) -> Result<&[u8], Error> {
+    match payload {
+        [READ_CFM, error, data @ ..] => {
+            check_error(*error)?;
+            Ok(data)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ReadV2 confirmation payload into the offset and the data read.
+pub fn parse_read_cfm_v2(payload: &[u8]) -> Result<(u32, &[u8]), Error> {
+    match payload {
+        [READ_CFM_V2, error, _, _, _, _, data @ ..] => {
+            check_error(*error)?;
+            let (offset, _) = parse_u32_be(payload, 2)?;
+            Ok((offset, data))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a WriteFile confirmation payload.
+pub fn parse_write_file_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [WRITE_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Sync confirmation payload.
+pub fn parse_flush_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [SYNC_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a LockFile confirmation payload.
+pub fn parse_lock_file_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [LOCK_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode an UnlockFile confirmation payload.
+pub fn parse_unlock_file_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [UNLOCK_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a DeleteFile confirmation payload.
+pub fn parse_delete_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [DELETE_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a RenameFile confirmation payload.
+pub fn parse_rename_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [RENAME_FILE_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a MakeDir confirmation payload.
+pub fn parse_make_dir_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [MAKE_DIR_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ChangeDir confirmation payload.
+pub fn parse_change_dir_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [CHANGE_DIR_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode an AudioStop indication payload into the reason playback stopped.
+pub fn parse_audio_stop_indication(payload: &[u8]) -> Result<AudioStopReason, Error> {
+    match payload {
+        [AUDIO_STOP_IND, reason, code] => AudioStopReason::from_wire(*reason, *code),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode an Interrupt indication payload. Unknown codes are not an error.
+pub fn parse_interrupt_indication(payload: &[u8]) -> Result<InterruptCode, Error> {
+    match payload {
+        [INTERRUPT_IND, code] => Ok(InterruptCode::from_wire(*code)),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Battery indication payload. Gives `Error::BadHeader` if the
+/// percentage or voltage is out of range.
+pub fn parse_battery_indication(payload: &[u8]) -> Result<BatteryIndication, Error> {
+    match payload {
+        [BATTERY_IND, args @ ..] => parse_battery_status(args),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Joystick indication payload.
+pub fn parse_joystick_indication(payload: &[u8]) -> Result<JoystickState, Error> {
+    match payload {
+        [JOYSTICK_IND, state] => JoystickState::from_wire(*state),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ScreenResize indication payload. Gives `Error::BadHeader` if
+/// either dimension is 0.
+pub fn parse_screen_resize_indication(payload: &[u8]) -> Result<ScreenResizeIndication, Error> {
+    match payload {
+        [SCREEN_RESIZE_IND, cols, rows] => ScreenResizeIndication::new(*cols, *rows),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Mouse indication payload. Gives `Error::BadHeader` if a
+/// reserved button bit is set.
+pub fn parse_mouse_indication(payload: &[u8]) -> Result<MouseIndication, Error> {
+    match payload {
+        [MOUSE_IND, dx, dy, buttons] => Ok(MouseIndication {
+            // Sign-extend from the i8 on the wire
+            delta_x: i16::from(*dx as i8),
+            delta_y: i16::from(*dy as i8),
+            buttons: MouseButtons::from_wire(*buttons)?,
+        }),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decodes the percentage, voltage and flags of a Battery indication.
+/// Reserved flag bits are ignored.
+fn parse_battery_status(args: &[u8]) -> Result<BatteryIndication, Error> {
+    match args {
+        [percent, _, _, flags] => {
+            let ind = BatteryIndication {
+                percent: *percent,
+                millivolts: parse_u16_be(args, 1)?.0,
+                charging: flags & BatteryIndication::FLAG_CHARGING != 0,
+            };
+            if !ind.is_valid() {
+                return Err(Error::BadHeader);
+            }
+            Ok(ind)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a SetVolumeLabel confirmation payload.
+pub fn parse_set_volume_label_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [SET_VOLUME_LABEL_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a GetVolumeLabel confirmation payload into the volume label,
+/// without the null terminator.
+pub fn parse_get_volume_label_cfm(payload: &[u8]) -> Result<&[u8], Error> {
+    match payload {
+        [GET_VOLUME_LABEL_CFM, error, rest @ ..] => {
+            check_error(*error)?;
+            match split_filename(rest)? {
+                (label, []) => Ok(label),
+                _ => Err(Error::BadHeader),
+            }
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a GetFileAttributes confirmation payload.
+pub fn parse_get_file_attributes_cfm(payload: &[u8]) -> Result<FileAttributes, Error> {
+    match payload {
+        [GET_FILE_ATTRIBUTES_CFM, error, attrs] => {
+            check_error(*error)?;
+            Ok(FileAttributes::from_wire(*attrs))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a SetFileAttributes confirmation payload.
+pub fn parse_set_file_attributes_cfm(payload: &[u8]) -> Result<(), Error> {
+    match payload {
+        [SET_FILE_ATTRIBUTES_CFM, error] => check_error(*error),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a GetMemoryInfo confirmation payload. Gives `Error::BadHeader` if
+/// the sizes don't fit in the total RAM.
+pub fn parse_get_memory_info_cfm(payload: &[u8]) -> Result<MemoryInfo, Error> {
+    match payload {
+        [GET_MEMORY_INFO_CFM, error, rest @ ..] => {
+            check_error(*error)?;
+            parse_memory_info(rest)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a DebugDump confirmation payload into the address and contents of
+/// one chunk of memory. Gives `Ok(None)` at the end of the dump.
+#[cfg(feature = "debug")]
+pub fn parse_debug_dump_cfm(payload: &[u8]) -> Result<Option<(u32, &[u8])>, Error> {
+    match payload {
+        [DEBUG_DUMP_CFM, error, _, _, _, _, data @ ..] => {
+            check_error(*error)?;
+            if data.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some((parse_u32_be(payload, 2)?.0, data)))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ScreenCapture confirmation payload into the next chunk of the
+/// screen. An empty chunk is the end of the capture.
+pub fn parse_screen_capture_cfm(payload: &[u8]) -> Result<&[u8], Error> {
+    match payload {
+        [SCREEN_CAPTURE_CFM, error, chunk @ ..] => {
+            check_error(*error)?;
+            Ok(chunk)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode an ExecuteFile confirmation payload into the program's exit code.
+pub fn parse_execute_cfm(payload: &[u8]) -> Result<i8, Error> {
+    match payload {
+        [EXECUTE_FILE_CFM, error, exit_code] => {
+            check_error(*error)?;
+            Ok(*exit_code as i8)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a GetWorkingDir confirmation payload into the path of the current
+/// directory, without the null terminator.
+pub fn parse_get_working_dir_cfm(payload: &[u8]) -> Result<&[u8], Error> {
+    match payload {
+        [GET_WORKING_DIR_CFM, error, rest @ ..] => {
+            check_error(*error)?;
+            match split_filename(rest)? {
+                (path, []) => Ok(path),
+                _ => Err(Error::BadHeader),
+            }
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a GetVersion confirmation payload into the PC's protocol version.
+pub fn parse_get_version_cfm(payload: &[u8]) -> Result<ProtocolVersion, Error> {
+    match payload {
+        [GET_VERSION_CFM, error, major, minor, patch] => {
+            check_error(*error)?;
+            Ok(ProtocolVersion {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+            })
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a SetBaudRate confirmation payload into the rate to switch to.
+pub fn parse_set_baud_rate_cfm(payload: &[u8]) -> Result<u32, Error> {
+    match payload {
+        [SET_BAUD_RATE_CFM, error, _, _, _, _] => {
+            check_error(*error)?;
+            parse_u32_be(payload, 2).map(|(baud, _)| baud)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a Checksum confirmation payload into the file's checksum.
+pub fn parse_checksum_cfm(payload: &[u8]) -> Result<u32, Error> {
+    match payload {
+        [CHECKSUM_CFM, error, _, _, _, _] => {
+            check_error(*error)?;
+            parse_u32_be(payload, 2).map(|(checksum, _)| checksum)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ChecksumV2 confirmation payload into the algorithm used and the
+/// file's checksum.
+pub fn parse_checksum_cfm_v2(payload: &[u8]) -> Result<(ChecksumAlgorithm, u32), Error> {
+    match payload {
+        [CHECKSUM_CFM_V2, error, algorithm, _, _, _, _] => {
+            check_error(*error)?;
+            let (checksum, _) = parse_u32_be(payload, 3)?;
+            Ok((ChecksumAlgorithm::from_wire(*algorithm), checksum))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode an OpenDir confirmation payload into the new directory handle.
+pub fn parse_open_dir_cfm(payload: &[u8]) -> Result<DirHandle, Error> {
+    match payload {
+        [OPEN_DIR_CFM, error, handle] => {
+            check_error(*error)?;
+            Ok(DirHandle::from_wire(*handle))
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ReadDir confirmation payload. Gives `Ok(None)` at the end of the
+/// directory, which the PC marks with a ReadDirEnd Confirmation or, on older
+/// PCs, with `Error::FileNotFound`.
+pub fn parse_read_dir_cfm(payload: &[u8]) -> Result<Option<DirEntry<'_>>, Error> {
+    match payload {
+        [READ_DIR_END_CFM] => Ok(None),
+        [READ_DIR_CFM, error, rest @ ..] => match check_error(*error) {
+            Ok(()) => parse_dir_entry(rest).map(Some),
+            Err(Error::FileNotFound) => Ok(None),
+            Err(e) => Err(e),
+        },
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode a ReadDirAt confirmation payload. `Error::BadOffset` means the
+/// directory has no entry with the index asked for.
+pub fn parse_read_dir_at_cfm(payload: &[u8]) -> Result<DirEntry<'_>, Error> {
+    match payload {
+        [READ_DIR_AT_CFM, error, rest @ ..] => {
+            check_error(*error)?;
+            parse_dir_entry(rest)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decode one of the Confirmations sent in reply to a SearchFiles request.
+/// Gives `Ok(None)` at the end of the results.
+pub fn parse_search_result_cfm(payload: &[u8]) -> Result<Option<DirEntry<'_>>, Error> {
+    match payload {
+        [SEARCH_RESULT_END_CFM] => Ok(None),
+        [SEARCH_RESULT_CFM, error, rest @ ..] => {
+            check_error(*error)?;
+            parse_dir_entry(rest).map(Some)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Decodes the total RAM, free RAM and stack usage, which must all fit.
+fn parse_memory_info(args: &[u8]) -> Result<MemoryInfo, Error> {
+    let (total_ram, offset) = parse_u32_be(args, 0)?;
+    let (free_ram, offset) = parse_u32_be(args, offset)?;
+    let (stack_usage, offset) = parse_u32_be(args, offset)?;
+    let info = MemoryInfo {
+        total_ram,
+        free_ram,
+        stack_usage,
+    };
+    if offset != args.len() || !info.is_valid() {
+        return Err(Error::BadHeader);
+    }
+    Ok(info)
+}
+
+/// Decodes the null-terminated name, size, mtime and type of a directory
+/// entry.
+fn parse_dir_entry(args: &[u8]) -> Result<DirEntry<'_>, Error> {
+    let (name, rest) = split_filename(args)?;
+    let (size, offset) = parse_u32_be(rest, 0)?;
+    match &rest[offset..] {
+        [mtime @ .., file_type] if mtime.len() == Timestamp::WIRE_LEN => Ok(DirEntry {
+            name,
+            size,
+            mtime: Timestamp::from_wire(&[
+                mtime[0], mtime[1], mtime[2], mtime[3], mtime[4], mtime[5],
+            ])?,
+            file_type: FileType::from_wire(*file_type)?,
+        }),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+fn need_escape(byte: u8) -> bool {
+    matches!(byte, END | ESC)
+}
+
+fn escape(byte: u8) -> u8 {
+    match byte {
+        END => ESC_END,
+        ESC => ESC_ESC,
+        x => x,
+    }
+}
+
+fn error_to_wire(error: Option<Error>) -> u8 {
+    error.map_or(wire::ERROR_SUCCESS, Error::to_wire)
+}
+
+fn error_from_wire(byte: u8) -> Result<Option<Error>, Error> {
+    match byte {
+        0x00 => Ok(None),
+        _ => Error::from_wire(byte).map(Some).ok_or(Error::BadHeader),
+    }
+}
+
+/// Turns the error byte in a confirmation into a `Result`.
+fn check_error(byte: u8) -> Result<(), Error> {
+    match error_from_wire(byte)? {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+/// Decode a CloseAll confirmation payload into the number of handles closed.
+pub fn parse_close_all_cfm(payload: &[u8]) -> Result<u8, Error> {
+    match payload {
+        [CLOSE_ALL_CFM, error, handles_closed] => {
+            check_error(*error)?;
+            Ok(*handles_closed)
+        }
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// The sequence number at the front of a frame sent with
+/// `CommandWriter::send_with_seq`. The rest of the payload follows it.
+#[cfg(debug_assertions)]
+pub fn sequence_number_from_payload(payload: &[u8]) -> Option<u8> {
+    payload.first().cloned()
+}
+
+/// Join `component` on to `base` with a `/`, writing the result into `buf`.
+/// Repeated slashes are written as one, and there is never a trailing slash
+/// (unless the result is just `/`). An empty `base` adds no `/`, so a
+/// relative `component` stays relative.
+pub fn path_join<'a>(base: &[u8], component: &[u8], buf: &'a mut [u8]) -> Result<&'a [u8], Error> {
+    let separator: &[u8] = if base.is_empty() { b"" } else { b"/" };
+    let mut len = 0;
+    let mut slash = false;
+    for &b in base.iter().chain(separator.iter()).chain(component.iter()) {
+        if b == b'/' {
+            slash = true;
+            continue;
+        }
+        if slash {
+            *buf.get_mut(len).ok_or(Error::BufferOverflow)? = b'/';
+            len += 1;
+            slash = false;
+        }
+        *buf.get_mut(len).ok_or(Error::BufferOverflow)? = b;
+        len += 1;
+    }
+    if len == 0 && slash {
+        *buf.get_mut(0).ok_or(Error::BufferOverflow)? = b'/';
+        len = 1;
+    }
+    Ok(&buf[0..len])
+}
+
+/// The directory containing `path`. The parent of a file in the root
+/// directory is `/`, as is the parent of `/` itself, and a path with no
+/// slashes has an empty parent.
+pub fn path_parent(path: &[u8]) -> &[u8] {
+    let trimmed = trim_trailing_slashes(path);
+    if trimmed.is_empty() && !path.is_empty() {
+        return &path[0..1];
+    }
+    match trimmed.iter().rposition(|&b| b == b'/') {
+        Some(idx) => {
+            let parent = trim_trailing_slashes(&trimmed[0..idx]);
+            if parent.is_empty() {
+                &path[0..1]
+            } else {
+                parent
+            }
+        }
+        None => &[],
+    }
+}
+
+fn trim_trailing_slashes(path: &[u8]) -> &[u8] {
+    let end = path
+        .iter()
+        .rposition(|&b| b != b'/')
+        .map_or(0, |idx| idx + 1);
+    &path[0..end]
+}
+
+/// Check that `cfm_payload` is a `PING_CFM_EXT` echoing the data in the
+/// `PING_REQ_EXT` in `req_payload`.
+pub fn verify_ping_echo(req_payload: &[u8], cfm_payload: &[u8]) -> bool {
+    match (req_payload.split_first(), cfm_payload.split_first()) {
+        (Some((&PING_REQ_EXT, req_data)), Some((&PING_CFM_EXT, cfm_data))) => req_data == cfm_data,
+        _ => false,
+    }
+}
+
+/// Gives `PROTOCOL_REVISION`.
+pub fn protocol_revision() -> &'static str {
+    PROTOCOL_REVISION
+}
+
+/// Can we talk to the other end, if it speaks protocol `revision`? It must
+/// have the same major version as us, and be no older than
+/// `MIN_COMPATIBLE_REVISION`.
+///
+/// Use this for a revision string. For a `ProtocolVersion`, such as one
+/// from a GetVersion Confirmation, use
+/// `ProtocolVersion::CURRENT.is_compatible_with`, which applies the same
+/// rule.
+pub fn is_compatible_with(revision: &str) -> bool {
+    match (
+        parse_revision(revision),
+        parse_revision(PROTOCOL_REVISION),
+        parse_revision(MIN_COMPATIBLE_REVISION),
+    ) {
+        (Some(theirs), Some(ours), Some(min)) => theirs.0 == ours.0 && theirs >= min,
+        _ => false,
+    }
+}
+
+fn parse_revision(revision: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = revision.split('.').map(|p| p.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => {
+            Some((major, minor, patch))
+        }
+        _ => None,
+    }
+}
+
+/// The bytes of a frame carrying `payload`, as `CommandWriter::get_byte`
+/// would give them out, but without a `CommandWriter` or its limit on
+/// payload length.
+pub fn framed_bytes_iter<'a>(payload: &'a [u8]) -> impl Iterator<Item = u8> + 'a {
+    let crc = compute_crc_x25(payload);
+    let body = payload
+        .iter()
+        .cloned()
+        .chain(iter::once((crc >> 8) as u8))
+        .chain(iter::once(crc as u8));
+    iter::once(END)
+        .chain(body.flat_map(|b| {
+            if need_escape(b) {
+                iter::once(ESC).chain(Some(escape(b)))
+            } else {
+                iter::once(b).chain(None)
+            }
+        }))
+        .chain(iter::once(END))
+}
+
+/// Adler-32, as described in RFC 1950.
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + u32::from(byte)) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// The length of the payload for opcodes which always have the same length.
+fn fixed_payload_len(opcode: u8) -> Option<usize> {
+    match opcode {
+        PING_REQ
+        | PING_CFM
+        | OPEN_DIR_REQ
+        | CLOSE_ALL_REQ
+        | READ_DIR_END_CFM
+        | GET_VERSION_REQ
+        | GET_WORKING_DIR_REQ
+        | SEARCH_RESULT_END_CFM
+        | GET_VOLUME_LABEL_REQ
+        | GET_MEMORY_INFO_REQ
+        | SCREEN_CAPTURE_REQ => Some(1),
+        CLOSE_FILE_REQ
+        | CHECKSUM_REQ
+        | CLOSE_DIR_REQ
+        | READ_DIR_REQ
+        | CHECKSUM_REQ_V2
+        | SYNC_REQ
+        | SET_VOLUME_LABEL_CFM
+        | SET_FILE_ATTRIBUTES_CFM
+        | LOCK_FILE_REQ
+        | UNLOCK_FILE_REQ
+        | LOCK_FILE_CFM
+        | UNLOCK_FILE_CFM
+        | CLOSE_FILE_CFM
+        | WRITE_FILE_CFM
+        | CLOSE_DIR_CFM
+        | SYNC_CFM
+        | DELETE_FILE_CFM
+        | RENAME_FILE_CFM
+        | MAKE_DIR_CFM
+        | CHANGE_DIR_CFM
+        | KEYPRESS_IND
+        | INTERRUPT_IND
+        | JOYSTICK_IND => Some(2),
+        OPEN_FILE_CFM
+        | OPEN_DIR_CFM
+        | CLOSE_ALL_CFM
+        | OPEN_FILE_CFM_V2
+        | EXECUTE_FILE_CFM
+        | GET_FILE_ATTRIBUTES_CFM
+        | AUDIO_STOP_IND
+        | SCREEN_RESIZE_IND => Some(3),
+        READ_DIR_AT_REQ | MOUSE_IND => Some(4),
+        GET_VERSION_CFM | SET_BAUD_RATE_REQ | BATTERY_IND => Some(5),
+        READ_REQ | CHECKSUM_CFM | SET_BAUD_RATE_CFM => Some(6),
+        CHECKSUM_CFM_V2 => Some(7),
+        READ_REQ_V2 => Some(8),
+        DEBUG_DUMP_REQ => Some(9),
+        GET_MEMORY_INFO_CFM => Some(14),
+        _ => None,
+    }
+}
+
+/// Reads a big-endian `u32` at `offset`, giving the value and the offset of
+/// the following byte.
+fn parse_u32_be(payload: &[u8], offset: usize) -> Result<(u32, usize), Error> {
+    match payload.get(offset..).and_then(|p| p.get(0..4)) {
+        Some(&[a, b, c, d]) => Ok((u32::from_be_bytes([a, b, c, d]), offset + 4)),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Reads a big-endian `u16` at `offset`, giving the value and the offset of
+/// the following byte.
+fn parse_u16_be(payload: &[u8], offset: usize) -> Result<(u16, usize), Error> {
+    match payload.get(offset..).and_then(|p| p.get(0..2)) {
+        Some(&[a, b]) => Ok((u16::from_be_bytes([a, b]), offset + 2)),
+        _ => Err(Error::BadHeader),
+    }
+}
+
+/// Splits a null-terminated filename from the bytes that follow it.
+fn split_filename(args: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let nul = args.iter().position(|&b| b == 0).ok_or(Error::BadHeader)?;
+    Ok((&args[0..nul], &args[nul + 1..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL_ERRORS: [Error; 11] = [
+        Error::BadChecksum,
+        Error::BadHeader,
+        Error::BufferOverflow,
+        Error::FileNotFound,
+        Error::BadOffset,
+        Error::IoError,
+        Error::PermissionDenied,
+        Error::AlreadyExists,
+        Error::ExecFormat,
+        Error::Locked,
+        Error::InvalidHandle,
+    ];
+
+    #[test]
+    fn basic_ping_req() {
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(cw.get_byte(), Some(PING_REQ));
+        assert_eq!(cw.get_byte(), Some(0xE1));
+        assert_eq!(cw.get_byte(), Some(0xF1));
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(cw.get_byte(), None);
+    }
+
+    #[test]
+    fn basic_ping_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_ping_cfm();
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(cw.get_byte(), Some(PING_CFM));
+        assert_eq!(cw.get_byte(), Some(0x65));
+        assert_eq!(cw.get_byte(), Some(0xF9));
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(cw.get_byte(), None);
+    }
+
+    #[test]
+    fn writer_counters() {
+        let mut cw = CommandWriter::new();
+        for _ in 0..2 {
+            cw.send_ping_req();
+            while cw.get_byte().is_some() {}
+        }
+        // Asking again after the end of the frame doesn't count
+        assert_eq!(cw.get_byte(), None);
+        assert_eq!(cw.frames_sent(), 2);
+        assert_eq!(cw.total_bytes_sent(), 2 * 5);
+        // Escape bytes are counted
+        cw.reset_counters();
+        cw.send_keypress_ind(END).unwrap();
+        while cw.get_byte().is_some() {}
+        assert_eq!(cw.frames_sent(), 1);
+        assert_eq!(cw.total_bytes_sent(), 7);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn into_framed_vec() {
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        let vec = cw.into_framed_vec::<8>().unwrap();
+        assert_eq!(&vec[..], &[END, PING_REQ, 0xE1, 0xF1, END]);
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        assert_eq!(cw.into_framed_vec::<4>(), Err(Error::BufferOverflow));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_to() {
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        let mut sink = std::io::Cursor::new(std::vec::Vec::new());
+        assert_eq!(cw.write_to(&mut sink).unwrap(), 5);
+        assert_eq!(&sink.get_ref()[..], &[END, PING_REQ, 0xE1, 0xF1, END]);
+        // Nothing left to send
+        assert_eq!(cw.write_to(&mut sink).unwrap(), 0);
+
+        // Longer than the bounce buffer, with escapes
+        let data = [END; 20];
+        cw.send_read_cfm(&data, None).unwrap();
+        let mut expected = std::vec::Vec::new();
+        let mut copy = CommandWriter::new();
+        copy.send_read_cfm(&data, None).unwrap();
+        while let Some(b) = copy.get_byte() {
+            expected.push(b);
+        }
+        let mut sink = std::io::Cursor::new(std::vec::Vec::new());
+        assert_eq!(cw.write_to(&mut sink).unwrap(), expected.len());
+        assert_eq!(sink.get_ref(), &expected);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_from() {
+        let mut src = std::io::Cursor::new(std::vec::Vec::new());
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        cw.write_to(src.get_mut()).unwrap();
+        cw.send_close_cfm(None).unwrap();
+        cw.write_to(src.get_mut()).unwrap();
+        src.set_position(0);
+        let mut cr = CommandReader::new();
+        assert_eq!(cr.read_from(&mut src).unwrap(), Some(&[PING_REQ][..]));
+        assert_eq!(src.position(), 5);
+        assert_eq!(
+            cr.read_from(&mut src).unwrap(),
+            Some(&[CLOSE_FILE_CFM, 0x00][..])
+        );
+        assert_eq!(cr.read_from(&mut src).unwrap(), None);
+        assert_eq!(cr.frames_received(), 2);
+    }
+
+    #[test]
+    fn crc_x25() {
+        assert_eq!(compute_crc_x25(&[PING_REQ]), 0xE1F1);
+        assert!(verify_crc_x25(&[PING_REQ], 0xE1, 0xF1));
+        assert!(!verify_crc_x25(&[PING_REQ], 0xF1, 0xE1));
+        assert_eq!(compute_crc_x25(&[PING_CFM]), 0x65F9);
+        // Check values from https://crccalc.com/
+        assert_eq!(compute_crc_x25(b"123456789"), 0x906E);
+        assert!(verify_crc_x25(b"123456789", 0x90, 0x6E));
+    }
+
+    #[test]
+    fn read_ping_req() {
+        let mut cr = CommandReader::new();
+        for &b in &[END, PING_REQ, 0xE1] {
+            assert_eq!(cr.push_byte(b), Ok(None));
+        }
+        assert_eq!(cr.push_byte(0xF1), Ok(None));
+        assert_eq!(cr.push_byte(END), Ok(Some(&[PING_REQ][..])));
+    }
+
+    #[test]
+    fn read_bad_checksum() {
+        let mut cr = CommandReader::new();
+        for &b in &[END, PING_REQ, 0xE1, 0xF2] {
+            assert_eq!(cr.push_byte(b), Ok(None));
+        }
+        assert_eq!(cr.push_byte(END), Err(Error::BadChecksum));
+    }
+
+    #[test]
+    fn reader_counters() {
+        let mut cr = CommandReader::new();
+        let frames: [&[u8]; 3] = [
+            &[END, PING_REQ, 0xE1, 0xF1, END],
+            &[END, PING_REQ, 0xE1, 0xF2, END],
+            &[END, PING_CFM, 0x65, 0xF9, END],
+        ];
+        for frame in frames.iter() {
+            for &b in frame.iter() {
+                let _ = cr.push_byte(b);
+            }
+        }
+        assert_eq!(cr.bytes_consumed(), 15);
+        assert_eq!(cr.frames_received(), 2);
+        assert_eq!(cr.checksum_errors(), 1);
+        cr.reset_counters();
+        assert_eq!(cr.bytes_consumed(), 0);
+        assert_eq!(cr.frames_received(), 0);
+        assert_eq!(cr.checksum_errors(), 0);
+    }
+
+    #[test]
+    fn force_resync() {
+        let mut cr = CommandReader::new();
+        assert!(cr.is_synchronised());
+        for &b in &[END, OPEN_FILE_REQ, b'A'] {
+            assert_eq!(cr.push_byte(b), Ok(None));
+        }
+        assert!(!cr.is_synchronised());
+        cr.force_resync();
+        assert!(cr.is_synchronised());
+        // Without the resync, the END would have ended the partial frame
+        for &b in &[END, PING_REQ, 0xE1, 0xF1] {
+            assert_eq!(cr.push_byte(b), Ok(None));
+        }
+        assert_eq!(cr.push_byte(END), Ok(Some(&[PING_REQ][..])));
+        assert!(cr.is_synchronised());
+    }
+
+    #[test]
+    fn read_overflow() {
+        let mut cr = CommandReader::new();
+        assert_eq!(cr.max_payload_capacity(), MAX_PAYLOAD);
+        assert!(!cr.overflow_occurred());
+        // The biggest frame we can send is fine
+        let mut cw = CommandWriter::new();
+        cw.send_read_cfm(&[0x55; MAX_PAYLOAD - 2], None).unwrap();
+        while let Some(b) = cw.get_byte() {
+            cr.push_byte(b).unwrap();
+        }
+        assert_eq!(cr.frames_received(), 1);
+        assert!(!cr.overflow_occurred());
+        // One more byte is too many
+        assert_eq!(cr.push_byte(END), Ok(None));
+        let mut overflows = 0;
+        for _ in 0..cr.max_payload_capacity() + CRC16_BYTES + 1 {
+            if cr.push_byte(0x55) == Err(Error::BufferOverflow) {
+                overflows += 1;
+            }
+        }
+        assert_eq!(overflows, 1);
+        assert!(cr.overflow_occurred());
+        cr.force_resync();
+        assert!(cr.overflow_occurred());
+        cr.reset();
+        assert!(!cr.overflow_occurred());
+    }
+
+    #[test]
+    fn read_escaped_bytes() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_cfm(&[END, ESC, 0x00], None).unwrap();
+        let mut wire = [0u8; 16];
+        let mut len = 0;
+        while let Some(b) = cw.get_byte() {
+            wire[len] = b;
+            len += 1;
+        }
+        assert_eq!(
+            &wire[1..8],
+            &[READ_CFM, 0x00, ESC, ESC_END, ESC, ESC_ESC, 0x00]
+        );
+        let mut cr = CommandReader::new();
+        for &b in &wire[0..len - 1] {
+            assert_eq!(cr.push_byte(b), Ok(None));
+        }
+        let payload = cr.push_byte(wire[len - 1]).unwrap().unwrap();
+        assert_eq!(
+            Message::parse(payload),
+            Ok(Message::Confirmation(Confirmation::Read {
+                data: &[END, ESC, 0x00],
+                error: None
+            }))
+        );
+    }
+
+    #[test]
+    fn open_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_open_cfm(Some(Handle::new(3)), None).unwrap();
+        assert_eq!(parse_open_cfm(&cw.bytes[0..cw.count]), Ok(Handle::new(3)));
+        cw.send_open_cfm(None, Some(Error::FileNotFound)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[OPEN_FILE_CFM, 0x04, 0x00]);
+        assert_eq!(
+            parse_open_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn open_dir_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_open_dir_cfm(Some(DirHandle::new(2)), None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[OPEN_DIR_CFM, 0x00, 0x02]);
+        let handle = parse_open_dir_cfm(&cw.bytes[0..cw.count]).unwrap();
+        assert_eq!(handle, DirHandle::new(2));
+        assert!(handle.is_valid());
+        cw.send_open_dir_cfm(None, Some(Error::FileNotFound))
+            .unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[OPEN_DIR_CFM, 0x04, 0x00]);
+        assert_eq!(
+            parse_open_dir_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::FileNotFound)
+        );
+        // A file confirmation is not a directory confirmation
+        cw.send_open_cfm(Some(Handle::new(2)), None).unwrap();
+        assert_eq!(
+            parse_open_dir_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn handle_display() {
+        use core::fmt::Write;
+        struct Buf([u8; 16], usize);
+        impl Write for Buf {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let end = self.1 + s.len();
+                self.0[self.1..end].copy_from_slice(s.as_bytes());
+                self.1 = end;
+                Ok(())
+            }
+        }
+        let mut buf = Buf([0u8; 16], 0);
+        write!(buf, "{} {}", Handle::new(1), DirHandle::new(1)).unwrap();
+        assert_eq!(&buf.0[0..buf.1], b"#1 dir #1");
+        assert!(!DirHandle::NULL.is_valid());
+        assert_eq!(DirHandle::from_wire(7).to_wire(), 7);
+    }
+
+    #[test]
+    fn encode_integers() {
+        let mut cw = CommandWriter::new();
+        cw.encode_u8(0x12).unwrap();
+        cw.encode_u16_be(0x3456).unwrap();
+        cw.encode_u32_be(0x789A_BCDE).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE]
+        );
+    }
+
+    #[test]
+    fn encode_overflow() {
+        let mut cw = CommandWriter::new();
+        cw.encode_bytes(&[0u8; MAX_PAYLOAD - 3]).unwrap();
+        assert_eq!(cw.encode_u32_be(0x1234_5678), Err(Error::BufferOverflow));
+        assert_eq!(cw.count, MAX_PAYLOAD - 3);
+        cw.encode_u16_be(0x1234).unwrap();
+        assert_eq!(cw.encode_u16_be(0x5678), Err(Error::BufferOverflow));
+        cw.encode_u8(0x56).unwrap();
+        assert_eq!(cw.encode_u8(0x78), Err(Error::BufferOverflow));
+        assert_eq!(cw.count, MAX_PAYLOAD);
+    }
+
+    #[test]
+    fn read_req_byte_order() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_req(Handle::new(1), 0x0102_0304).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[READ_REQ, 0x01, 0x01, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(
+            cw.send_read_cfm(&[0u8; MAX_PAYLOAD - 1], None),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(
+            cw.send_open_file_req(&[b'A'; MAX_PAYLOAD - 2], Mode::Read),
+            Err(Error::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn parse_integers() {
+        let payload = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC];
+        assert_eq!(parse_u32_be(&payload, 0), Ok((0x1234_5678, 4)));
+        assert_eq!(
+            parse_u32_be(&payload, payload.len() - 4),
+            Ok((0x5678_9ABC, 6))
+        );
+        assert_eq!(
+            parse_u32_be(&payload, payload.len() - 3),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(parse_u16_be(&payload, payload.len() - 2), Ok((0x9ABC, 6)));
+        assert_eq!(
+            parse_u16_be(&payload, payload.len() - 1),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_u16_be(&payload, payload.len() + 1),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(parse_u32_be(&payload, usize::MAX), Err(Error::BadHeader));
+        assert_eq!(parse_u32_be(&[], 0), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn opcode_ranges() {
+        let pairs = [
+            (opcodes::PING_REQ, opcodes::PING_CFM),
+            (opcodes::OPEN_FILE_REQ, opcodes::OPEN_FILE_CFM),
+            (opcodes::CLOSE_FILE_REQ, opcodes::CLOSE_FILE_CFM),
+            (opcodes::READ_REQ, opcodes::READ_CFM),
+            (opcodes::CHECKSUM_REQ, opcodes::CHECKSUM_CFM),
+            (opcodes::OPEN_DIR_REQ, opcodes::OPEN_DIR_CFM),
+            (opcodes::CLOSE_DIR_REQ, opcodes::CLOSE_DIR_CFM),
+            (opcodes::READ_DIR_REQ, opcodes::READ_DIR_CFM),
+            (opcodes::CLOSE_ALL_REQ, opcodes::CLOSE_ALL_CFM),
+            (opcodes::WRITE_FILE_REQ, opcodes::WRITE_FILE_CFM),
+            (opcodes::PING_REQ_EXT, opcodes::PING_CFM_EXT),
+            (opcodes::READ_REQ_V2, opcodes::READ_CFM_V2),
+            (opcodes::OPEN_FILE_REQ_V2, opcodes::OPEN_FILE_CFM_V2),
+            (opcodes::CHECKSUM_REQ_V2, opcodes::CHECKSUM_CFM_V2),
+            (opcodes::SYNC_REQ, opcodes::SYNC_CFM),
+            (opcodes::DELETE_FILE_REQ, opcodes::DELETE_FILE_CFM),
+            (opcodes::RENAME_FILE_REQ, opcodes::RENAME_FILE_CFM),
+            (opcodes::GET_VERSION_REQ, opcodes::GET_VERSION_CFM),
+            (opcodes::SET_BAUD_RATE_REQ, opcodes::SET_BAUD_RATE_CFM),
+            (opcodes::READ_DIR_AT_REQ, opcodes::READ_DIR_AT_CFM),
+            (opcodes::MAKE_DIR_REQ, opcodes::MAKE_DIR_CFM),
+            (opcodes::CHANGE_DIR_REQ, opcodes::CHANGE_DIR_CFM),
+            (opcodes::GET_WORKING_DIR_REQ, opcodes::GET_WORKING_DIR_CFM),
+            (opcodes::SEARCH_FILES_REQ, opcodes::SEARCH_RESULT_CFM),
+            (opcodes::EXECUTE_FILE_REQ, opcodes::EXECUTE_FILE_CFM),
+            (opcodes::LOCK_FILE_REQ, opcodes::LOCK_FILE_CFM),
+            (opcodes::UNLOCK_FILE_REQ, opcodes::UNLOCK_FILE_CFM),
+            (opcodes::SET_VOLUME_LABEL_REQ, opcodes::SET_VOLUME_LABEL_CFM),
+            (opcodes::GET_VOLUME_LABEL_REQ, opcodes::GET_VOLUME_LABEL_CFM),
+            (
+                opcodes::GET_FILE_ATTRIBUTES_REQ,
+                opcodes::GET_FILE_ATTRIBUTES_CFM,
+            ),
+            (
+                opcodes::SET_FILE_ATTRIBUTES_REQ,
+                opcodes::SET_FILE_ATTRIBUTES_CFM,
+            ),
+            (opcodes::GET_MEMORY_INFO_REQ, opcodes::GET_MEMORY_INFO_CFM),
+            (opcodes::DEBUG_DUMP_REQ, opcodes::DEBUG_DUMP_CFM),
+            (opcodes::SCREEN_CAPTURE_REQ, opcodes::SCREEN_CAPTURE_CFM),
+        ];
+        for &(req, cfm) in pairs.iter() {
+            assert!(req > 0x00 && req < 0x70, "request {:#x}", req);
+            assert_eq!(cfm & 0x80, 0x80, "confirmation {:#x}", cfm);
+            assert_eq!(cfm, req | 0x80);
+            assert_eq!(opcode_direction(req), OpcodeDirection::Request);
+            assert_eq!(opcode_direction(cfm), OpcodeDirection::Confirmation);
+        }
+        let indications = [
+            opcodes::KEYPRESS_IND,
+            opcodes::KEYPRESS_BATCH_IND,
+            opcodes::AUDIO_STOP_IND,
+            opcodes::INTERRUPT_IND,
+            opcodes::BATTERY_IND,
+            opcodes::JOYSTICK_IND,
+            opcodes::SCREEN_RESIZE_IND,
+            opcodes::MOUSE_IND,
+        ];
+        for &ind in indications.iter() {
+            assert!(ind >= 0xF0, "indication {:#x}", ind);
+            assert_eq!(opcode_direction(ind), OpcodeDirection::Indication);
+        }
+        for &unused in &[0x00, 0x70, 0x7F, 0x80] {
+            assert_eq!(opcode_direction(unused), OpcodeDirection::Unknown);
+        }
+    }
+
+    #[test]
+    fn indications() {
+        assert!(is_indication_opcode(opcodes::KEYPRESS_IND));
+        assert!(is_indication_opcode(0xFF));
+        assert!(!is_indication_opcode(opcodes::PING_REQ));
+        assert!(!is_indication_opcode(opcodes::READ_DIR_CFM));
+        assert!(!is_indication_opcode(0xEF));
+        let mut cw = CommandWriter::new();
+        cw.send_indication(0xF7, &[1, 2, 3]).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[0xF7, 1, 2, 3]);
+        assert_eq!(
+            cw.send_indication(opcodes::PING_CFM, &[]),
+            Err(Error::BadHeader)
+        );
+        cw.send_keypress_ind(b'x').unwrap();
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Indication(Indication::Keypress(b'x')))
+        );
+    }
+
+    #[test]
+    fn send_every_request() {
+        let requests = [
+            Request::Ping,
+            Request::OpenFile {
+                filename: b"A.TXT",
+                mode: Mode::ReadWrite,
+            },
+            Request::CloseFile {
+                handle: Handle::new(1),
+            },
+            Request::Read {
+                handle: Handle::new(1),
+                offset: 1234,
+            },
+            Request::Checksum {
+                handle: Handle::new(1),
+            },
+            Request::OpenDir,
+            Request::CloseDir {
+                handle: DirHandle::new(2),
+            },
+            Request::ReadDir {
+                handle: DirHandle::new(2),
+            },
+            Request::CloseAll,
+            Request::WriteFile {
+                handle: Handle::new(1),
+                offset: 5678,
+                data: b"xyz",
+            },
+            Request::PingExt { data: b"echo" },
+            Request::ReadV2 {
+                handle: Handle::new(1),
+                offset: 0x0102_0304,
+                count: 300,
+            },
+            Request::OpenFileV2 {
+                filename: b"B.TXT",
+                mode: Mode::Write,
+                size_hint: 4096,
+            },
+            Request::ChecksumV2 {
+                handle: Handle::new(1),
+            },
+            Request::Sync {
+                handle: Handle::new(1),
+            },
+            Request::DeleteFile {
+                filename: b"OLD.TXT",
+            },
+            Request::RenameFile {
+                old: b"A.TXT",
+                new: b"B.TXT",
+            },
+            Request::GetVersion,
+            Request::SetBaudRate { baud: 115_200 },
+            Request::ReadDirAt {
+                handle: DirHandle::new(2),
+                index: 0x0102,
+            },
+            Request::MakeDir { dirname: b"DOCS" },
+            Request::ChangeDir { dirname: b"DOCS" },
+            Request::GetWorkingDir,
+            Request::SearchFiles { pattern: b"*.BAS" },
+            Request::ExecuteFile {
+                filename: b"SNAKE.BAS",
+            },
+            Request::LockFile {
+                handle: Handle::new(1),
+            },
+            Request::UnlockFile {
+                handle: Handle::new(1),
+            },
+            Request::SetVolumeLabel { label: b"MONOTRON" },
+            Request::GetVolumeLabel,
+            Request::GetFileAttributes { filename: b"A.TXT" },
+            Request::SetFileAttributes {
+                filename: b"A.TXT",
+                attrs: FileAttributes::HIDDEN,
+            },
+            Request::GetMemoryInfo,
+            Request::ScreenCapture,
+        ];
+        let mut cw = CommandWriter::new();
+        for req in requests.iter() {
+            cw.send_request(req).unwrap();
+            assert_eq!(
+                Message::parse(&cw.bytes[0..cw.count]),
+                Ok(Message::Request(*req))
+            );
+        }
+    }
+
+    #[test]
+    fn send_every_confirmation() {
+        let entry = DirEntry {
+            name: b"A.TXT",
+            size: 10,
+            mtime: MTIME,
+            file_type: FileType::File,
+        };
+        let confirmations = [
+            Confirmation::Ping,
+            Confirmation::OpenFile {
+                handle: Handle::new(1),
+                error: None,
+            },
+            Confirmation::CloseFile {
+                error: Some(Error::BadHeader),
+            },
+            Confirmation::Read {
+                data: b"abc",
+                error: None,
+            },
+            Confirmation::Checksum {
+                checksum: 0x1234_5678,
+                error: None,
+            },
+            Confirmation::OpenDir {
+                handle: DirHandle::NULL,
+                error: Some(Error::FileNotFound),
+            },
+            Confirmation::CloseDir { error: None },
+            Confirmation::ReadDir {
+                entry: Some(entry),
+                error: None,
+            },
+            Confirmation::ReadDir {
+                entry: None,
+                error: Some(Error::FileNotFound),
+            },
+            Confirmation::CloseAll {
+                handles_closed: 3,
+                error: None,
+            },
+            Confirmation::WriteFile { error: None },
+            Confirmation::PingExt { data: b"echo" },
+            Confirmation::ReadV2 {
+                offset: 0x0102_0304,
+                data: b"abc",
+                error: None,
+            },
+            Confirmation::OpenFileV2 {
+                handle: Handle::new(4),
+                error: None,
+            },
+            Confirmation::ChecksumV2 {
+                algorithm: ChecksumAlgorithm::Adler32,
+                checksum: 0x11E6_0398,
+                error: None,
+            },
+            Confirmation::ReadDirEnd,
+            Confirmation::Sync {
+                error: Some(Error::IoError),
+            },
+            Confirmation::DeleteFile {
+                error: Some(Error::PermissionDenied),
+            },
+            Confirmation::RenameFile {
+                error: Some(Error::AlreadyExists),
+            },
+            Confirmation::GetVersion {
+                version: ProtocolVersion::CURRENT,
+                error: None,
+            },
+            Confirmation::SetBaudRate {
+                accepted_baud: 115_200,
+                error: None,
+            },
+            Confirmation::ReadDirAt {
+                entry: Some(entry),
+                error: None,
+            },
+            Confirmation::ReadDirAt {
+                entry: None,
+                error: Some(Error::BadOffset),
+            },
+            Confirmation::MakeDir {
+                error: Some(Error::AlreadyExists),
+            },
+            Confirmation::ChangeDir {
+                error: Some(Error::FileNotFound),
+            },
+            Confirmation::GetWorkingDir {
+                path: b"/GAMES",
+                error: None,
+            },
+            Confirmation::SearchResult {
+                entry: Some(entry),
+                error: None,
+            },
+            Confirmation::SearchResult {
+                entry: None,
+                error: Some(Error::BadHeader),
+            },
+            Confirmation::SearchResultEnd,
+            Confirmation::ExecuteFile {
+                exit_code: -1,
+                error: None,
+            },
+            Confirmation::ExecuteFile {
+                exit_code: 0,
+                error: Some(Error::ExecFormat),
+            },
+            Confirmation::LockFile {
+                error: Some(Error::Locked),
+            },
+            Confirmation::UnlockFile {
+                error: Some(Error::InvalidHandle),
+            },
+            Confirmation::SetVolumeLabel {
+                error: Some(Error::PermissionDenied),
+            },
+            Confirmation::GetVolumeLabel {
+                label: b"MONOTRON",
+                error: None,
+            },
+            Confirmation::GetFileAttributes {
+                attrs: FileAttributes::READ_ONLY | FileAttributes::ARCHIVE,
+                error: None,
+            },
+            Confirmation::SetFileAttributes {
+                error: Some(Error::PermissionDenied),
+            },
+            Confirmation::GetMemoryInfo {
+                info: MemoryInfo {
+                    total_ram: 0x0000_8000,
+                    free_ram: 0x0000_1234,
+                    stack_usage: 0x0000_0400,
+                },
+                error: None,
+            },
+            Confirmation::ScreenCapture {
+                chunk: b"Monotron",
+                error: None,
+            },
+            Confirmation::ScreenCapture {
+                chunk: b"",
+                error: None,
+            },
+        ];
+        let mut cw = CommandWriter::new();
+        for cfm in confirmations.iter() {
+            cw.send_confirmation(cfm).unwrap();
+            assert_eq!(
+                Message::parse(&cw.bytes[0..cw.count]),
+                Ok(Message::Confirmation(*cfm))
+            );
+        }
+    }
+
+    #[test]
+    fn frame_sizes() {
+        assert_eq!(MIN_FRAME_OVERHEAD, 4);
+        assert_eq!(MIN_FRAME_SIZE, 5);
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        let mut len = 0;
+        while cw.get_byte().is_some() {
+            len += 1;
+        }
+        assert_eq!(len, MIN_FRAME_SIZE);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn validate_payload() {
+        let mut cw = CommandWriter::new();
+        assert_eq!(cw.validate_payload(), Err(Error::BadHeader));
+        cw.send_open_file_req(b"A.TXT", Mode::Read).unwrap();
+        assert_eq!(cw.validate_payload(), Ok(()));
+        cw.send_read_dir_cfm(
+            Some(&DirEntry {
+                name: b"A.TXT",
+                size: 1,
+                mtime: MTIME,
+                file_type: FileType::File,
+            }),
+            None,
+        )
+        .unwrap();
+        assert_eq!(cw.validate_payload(), Ok(()));
+        // A filename without its terminating null
+        cw.send_raw_bytes(&[OPEN_FILE_REQ, b'A', 0x01]).unwrap();
+        assert_eq!(cw.validate_payload(), Err(Error::BadHeader));
+        // Too short for the offset
+        cw.send_raw_bytes(&[READ_REQ, 0x01, 0x00]).unwrap();
+        assert_eq!(cw.validate_payload(), Err(Error::BadHeader));
+        // An error byte this crate doesn't know
+        cw.send_raw_bytes(&[CLOSE_FILE_CFM, 0x7F]).unwrap();
+        assert_eq!(cw.validate_payload(), Err(Error::BadHeader));
+        // An impossible timestamp
+        cw.send_raw_bytes(&[
+            READ_DIR_CFM,
+            0x00,
+            b'A',
+            0x00,
+            0,
+            0,
+            0,
+            1,
+            48,
+            13,
+            1,
+            0,
+            0,
+            0,
+            0x00,
+        ])
+        .unwrap();
+        assert_eq!(cw.validate_payload(), Err(Error::BadHeader));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn sequence_numbers() {
+        let mut cw = CommandWriter::new();
+        cw.send_close_file_req(Handle::new(2)).unwrap();
+        cw.send_with_seq(0x42).unwrap();
+        let mut cr = CommandReader::new();
+        let mut found = false;
+        while let Some(b) = cw.get_byte() {
+            if let Some(payload) = cr.push_byte(b).unwrap() {
+                assert_eq!(sequence_number_from_payload(payload), Some(0x42));
+                assert_eq!(
+                    Message::parse(&payload[1..]),
+                    Ok(Message::Request(Request::CloseFile {
+                        handle: Handle::new(2)
+                    }))
+                );
+                found = true;
+            }
+        }
+        assert!(found);
+        assert_eq!(sequence_number_from_payload(&[]), None);
+        cw.send_read_cfm(&[0u8; MAX_PAYLOAD - 2], None).unwrap();
+        assert_eq!(cw.send_with_seq(1), Err(Error::BufferOverflow));
+    }
+
+    #[test]
+    fn path_join_absolute() {
+        let mut buf = [0u8; 16];
+        assert_eq!(path_join(b"/", b"GAMES", &mut buf), Ok(&b"/GAMES"[..]));
+        assert_eq!(
+            path_join(b"/GAMES", b"SNAKE.BAS", &mut buf),
+            Ok(&b"/GAMES/SNAKE.BAS"[..])
+        );
+        assert_eq!(
+            path_join(b"//GAMES", b"//A", &mut buf),
+            Ok(&b"/GAMES/A"[..])
+        );
+        assert_eq!(path_join(b"GAMES", b"A", &mut buf), Ok(&b"GAMES/A"[..]));
+    }
+
+    #[test]
+    fn path_join_trailing_slash() {
+        let mut buf = [0u8; 16];
+        assert_eq!(path_join(b"/GAMES/", b"A", &mut buf), Ok(&b"/GAMES/A"[..]));
+        assert_eq!(path_join(b"/GAMES", b"A/", &mut buf), Ok(&b"/GAMES/A"[..]));
+    }
+
+    #[test]
+    fn path_join_empty_component() {
+        let mut buf = [0u8; 16];
+        assert_eq!(path_join(b"/GAMES/", b"", &mut buf), Ok(&b"/GAMES"[..]));
+        assert_eq!(path_join(b"/", b"", &mut buf), Ok(&b"/"[..]));
+        // An empty base adds no slash, so this stays empty
+        assert_eq!(path_join(b"", b"", &mut buf), Ok(&b""[..]));
+    }
+
+    #[test]
+    fn path_join_empty_base() {
+        let mut buf = [0u8; 16];
+        assert_eq!(path_join(b"", b"A", &mut buf), Ok(&b"A"[..]));
+        assert_eq!(path_join(b"", b"GAMES/A", &mut buf), Ok(&b"GAMES/A"[..]));
+        assert_eq!(path_join(b"", b"/A", &mut buf), Ok(&b"/A"[..]));
+        assert_eq!(path_join(b"GAMES", b"A", &mut buf), Ok(&b"GAMES/A"[..]));
+    }
+
+    #[test]
+    fn path_join_overflow() {
+        let mut buf = [0u8; 8];
+        assert_eq!(path_join(b"/GAMES", b"A", &mut buf), Ok(&b"/GAMES/A"[..]));
+        assert_eq!(
+            path_join(b"/GAMES", b"AB", &mut buf),
+            Err(Error::BufferOverflow)
+        );
+        // A trailing slash that is dropped doesn't need space
+        assert_eq!(path_join(b"/GAMES/A", b"/", &mut buf), Ok(&b"/GAMES/A"[..]));
+        assert_eq!(path_join(b"/", b"", &mut []), Err(Error::BufferOverflow));
+    }
+
+    #[test]
+    fn path_parent() {
+        assert_eq!(super::path_parent(b"/GAMES/SNAKE.BAS"), b"/GAMES");
+        assert_eq!(super::path_parent(b"/GAMES//SNAKE.BAS"), b"/GAMES");
+        assert_eq!(super::path_parent(b"/GAMES/SUB/"), b"/GAMES");
+        assert_eq!(super::path_parent(b"/GAMES"), b"/");
+        assert_eq!(super::path_parent(b"SNAKE.BAS"), b"");
+        assert_eq!(super::path_parent(b"A/B"), b"A");
+        assert_eq!(super::path_parent(b"/"), b"/");
+        assert_eq!(super::path_parent(b"//"), b"/");
+        assert_eq!(super::path_parent(b""), b"");
+    }
+
+    #[test]
+    fn ping_echo() {
+        let zeros = [0x00u8; MAX_PAYLOAD - 1];
+        let ones = [0xFFu8; MAX_PAYLOAD - 1];
+        let mut pattern = [0u8; MAX_PAYLOAD - 1];
+        // 8-bit maximal-length Galois LFSR
+        let mut lfsr = 0xA5u8;
+        for b in pattern.iter_mut() {
+            *b = lfsr;
+            lfsr = (lfsr >> 1) ^ if lfsr & 1 != 0 { 0xB8 } else { 0 };
+        }
+        for data in [&zeros[..], &ones[..], &pattern[..]].iter() {
+            let mut req = CommandWriter::new();
+            req.send_ping_req_ext(data).unwrap();
+            let req_payload = &req.bytes[0..req.count];
+            let mut cfm = CommandWriter::new();
+            cfm.send_ping_cfm_echo(req_payload).unwrap();
+            let cfm_payload = &cfm.bytes[0..cfm.count];
+            assert_eq!(cfm_payload[0], PING_CFM_EXT);
+            assert!(verify_ping_echo(req_payload, cfm_payload));
+            let mut corrupt = [0u8; MAX_PAYLOAD];
+            corrupt[0..cfm.count].copy_from_slice(cfm_payload);
+            corrupt[5] ^= 0x01;
+            assert!(!verify_ping_echo(req_payload, &corrupt[0..cfm.count]));
+            assert!(!verify_ping_echo(req_payload, &cfm_payload[0..4]));
+        }
+        let mut cw = CommandWriter::new();
+        assert_eq!(cw.send_ping_cfm_echo(&[PING_REQ]), Err(Error::BadHeader));
+        assert_eq!(cw.send_ping_cfm_echo(&[]), Err(Error::BadHeader));
+        assert!(!verify_ping_echo(&[PING_REQ], &[PING_CFM]));
+    }
+
+    #[test]
+    fn payload_crc() {
+        let mut cw = CommandWriter::new();
+        assert_eq!(cw.payload_crc(), None);
+        cw.send_ping_req();
+        // Sent as 0xE1 then 0xF1
+        assert_eq!(cw.payload_crc(), Some(0xE1F1));
+        while cw.get_byte().is_some() {}
+        assert_eq!(cw.payload_crc(), Some(0xE1F1));
+        cw.send_ping_cfm();
+        assert_eq!(cw.payload_crc(), Some(0x65F9));
+        cw.reset();
+        assert_eq!(cw.payload_crc(), None);
+    }
+
+    #[test]
+    fn new_writer_sends_nothing() {
+        assert_eq!(CommandWriter::new().get_byte(), None);
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        cw.reset();
+        assert_eq!(cw.get_byte(), None);
+    }
+
+    #[test]
+    fn failed_build_sends_nothing() {
+        let mut cw = CommandWriter::new();
+        assert_eq!(cw.send_read_cfm(&[0; 40], None), Err(Error::BufferOverflow));
+        assert_eq!(cw.get_byte(), None);
+        assert_eq!(cw.payload_crc(), None);
+
+        // Nor is the previous frame, whether it failed part way through
+        // encoding or before it started
+        cw.send_ping_req();
+        assert_eq!(cw.send_read_cfm(&[0; 40], None), Err(Error::BufferOverflow));
+        assert_eq!(cw.get_byte(), None);
+        assert_eq!(cw.payload_crc(), None);
+        cw.send_ping_req();
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(
+            cw.send_open_file_req(b"A\0B", Mode::Read),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(cw.get_byte(), None);
+        assert_eq!(cw.payload_crc(), None);
+
+        cw.send_ping_cfm();
+        assert_eq!(cw.get_byte(), Some(END));
+        assert_eq!(cw.get_byte(), Some(PING_CFM));
+    }
+
+    #[test]
+    fn protocol_revision() {
+        assert_eq!(super::protocol_revision(), PROTOCOL_REVISION);
+        assert!(is_compatible_with(PROTOCOL_REVISION));
+        assert!(is_compatible_with(MIN_COMPATIBLE_REVISION));
+        assert!(is_compatible_with("0.9.3"));
+        assert!(!is_compatible_with("1.0.0"));
+        assert!(!is_compatible_with("0.1"));
+        assert!(!is_compatible_with("0.1.0.0"));
+        assert!(!is_compatible_with("0.x.0"));
+        assert!(!is_compatible_with(""));
+    }
+
+    #[test]
+    fn append_mode() {
+        assert_ne!(Mode::Append.wire_byte(), Mode::ReadWrite.wire_byte());
+        assert_ne!(Mode::Append.wire_byte(), Mode::Write.wire_byte());
+        assert_eq!(Mode::from_wire(Mode::Append.wire_byte()), Ok(Mode::Append));
+        let mut append = CommandWriter::new();
+        append.send_open_file_req(b"LOG.TXT", Mode::Append).unwrap();
+        let mut read_write = CommandWriter::new();
+        read_write
+            .send_open_file_req(b"LOG.TXT", Mode::ReadWrite)
+            .unwrap();
+        assert_eq!(append.count, read_write.count);
+        let differences: usize = append.bytes[0..append.count]
+            .iter()
+            .zip(read_write.bytes[0..read_write.count].iter())
+            .filter(|&(a, b)| a != b)
+            .count();
+        assert_eq!(differences, 1);
+        assert_eq!(append.bytes[append.count - 1], Mode::Append.wire_byte());
+    }
+
+    #[test]
+    fn read_cfm_eof() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_cfm_eof().unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[READ_CFM, 0x00]);
+        assert_eq!(parse_read_cfm(&cw.bytes[0..cw.count]), Ok(&[][..]));
+        cw.send_read_cfm(b"abc", None).unwrap();
+        assert_eq!(parse_read_cfm(&cw.bytes[0..cw.count]), Ok(&b"abc"[..]));
+        cw.send_read_cfm(&[], Some(Error::BadOffset)).unwrap();
+        assert_eq!(
+            parse_read_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadOffset)
+        );
+        assert_eq!(parse_read_cfm(&[READ_CFM]), Err(Error::BadHeader));
+        assert_eq!(
+            parse_read_cfm(&[CLOSE_FILE_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn open_cfm_not_found() {
+        let mut shorthand = CommandWriter::new();
+        shorthand.send_open_cfm_not_found().unwrap();
+        let mut general = CommandWriter::new();
+        general
+            .send_open_cfm(None, Some(Error::FileNotFound))
+            .unwrap();
+        assert_eq!(
+            &shorthand.bytes[0..shorthand.count],
+            &general.bytes[0..general.count]
+        );
+        assert_eq!(
+            parse_open_cfm(&shorthand.bytes[0..shorthand.count]),
+            Err(Error::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn request_confirmation_pairs() {
+        let handle = Handle::new(1);
+        let dir = DirHandle::new(2);
+        let pairs = [
+            (Request::Ping, Confirmation::Ping),
+            (
+                Request::PingExt { data: b"x" },
+                Confirmation::PingExt { data: b"x" },
+            ),
+            (
+                Request::OpenFile {
+                    filename: b"A.TXT",
+                    mode: Mode::Read,
+                },
+                Confirmation::OpenFile {
+                    handle,
+                    error: None,
+                },
+            ),
+            (
+                Request::CloseFile { handle },
+                Confirmation::CloseFile { error: None },
+            ),
+            (
+                Request::Read { handle, offset: 0 },
+                Confirmation::Read {
+                    data: b"",
+                    error: None,
+                },
+            ),
+            (
+                Request::WriteFile {
+                    handle,
+                    offset: 0,
+                    data: b"x",
+                },
+                Confirmation::WriteFile { error: None },
+            ),
+            (
+                Request::Checksum { handle },
+                Confirmation::Checksum {
+                    checksum: 0,
+                    error: None,
+                },
+            ),
+            (
+                Request::OpenDir,
+                Confirmation::OpenDir {
+                    handle: dir,
+                    error: None,
+                },
+            ),
+            (
+                Request::CloseDir { handle: dir },
+                Confirmation::CloseDir { error: None },
+            ),
+            (
+                Request::ReadDir { handle: dir },
+                Confirmation::ReadDir {
+                    entry: None,
+                    error: Some(Error::FileNotFound),
+                },
+            ),
+            (
+                Request::CloseAll,
+                Confirmation::CloseAll {
+                    handles_closed: 0,
+                    error: None,
+                },
+            ),
+            (
+                Request::ReadV2 {
+                    handle,
+                    offset: 8,
+                    count: 16,
+                },
+                Confirmation::ReadV2 {
+                    offset: 8,
+                    data: b"",
+                    error: None,
+                },
+            ),
+            (
+                Request::OpenFileV2 {
+                    filename: b"A.TXT",
+                    mode: Mode::Write,
+                    size_hint: 0,
+                },
+                Confirmation::OpenFileV2 {
+                    handle,
+                    error: None,
+                },
+            ),
+            (
+                Request::ChecksumV2 { handle },
+                Confirmation::ChecksumV2 {
+                    algorithm: ChecksumAlgorithm::Crc32Iso,
+                    checksum: 0,
+                    error: None,
+                },
+            ),
+            (Request::Sync { handle }, Confirmation::Sync { error: None }),
+            (
+                Request::DeleteFile { filename: b"A.TXT" },
+                Confirmation::DeleteFile { error: None },
+            ),
+            (
+                Request::RenameFile {
+                    old: b"A.TXT",
+                    new: b"B.TXT",
+                },
+                Confirmation::RenameFile { error: None },
+            ),
+            (
+                Request::GetVersion,
+                Confirmation::GetVersion {
+                    version: ProtocolVersion::CURRENT,
+                    error: None,
+                },
+            ),
+            (
+                Request::SetBaudRate { baud: 9600 },
+                Confirmation::SetBaudRate {
+                    accepted_baud: 9600,
+                    error: None,
+                },
+            ),
+            (
+                Request::ReadDirAt {
+                    handle: DirHandle::new(1),
+                    index: 1,
+                },
+                Confirmation::ReadDirAt {
+                    entry: None,
+                    error: Some(Error::BadOffset),
+                },
+            ),
+            (
+                Request::MakeDir { dirname: b"DOCS" },
+                Confirmation::MakeDir { error: None },
+            ),
+            (
+                Request::ChangeDir { dirname: b"DOCS" },
+                Confirmation::ChangeDir { error: None },
+            ),
+            (
+                Request::GetWorkingDir,
+                Confirmation::GetWorkingDir {
+                    path: b"/",
+                    error: None,
+                },
+            ),
+            (
+                Request::SearchFiles { pattern: b"*.BAS" },
+                Confirmation::SearchResult {
+                    entry: None,
+                    error: Some(Error::FileNotFound),
+                },
+            ),
+            (
+                Request::ExecuteFile { filename: b"A.BAS" },
+                Confirmation::ExecuteFile {
+                    exit_code: 0,
+                    error: None,
+                },
+            ),
+            (
+                Request::LockFile {
+                    handle: Handle::new(2),
+                },
+                Confirmation::LockFile { error: None },
+            ),
+            (
+                Request::UnlockFile {
+                    handle: Handle::new(2),
+                },
+                Confirmation::UnlockFile { error: None },
+            ),
+            (
+                Request::SetVolumeLabel { label: b"DISK" },
+                Confirmation::SetVolumeLabel { error: None },
+            ),
+            (
+                Request::GetVolumeLabel,
+                Confirmation::GetVolumeLabel {
+                    label: b"DISK",
+                    error: None,
+                },
+            ),
+            (
+                Request::GetFileAttributes { filename: b"A.TXT" },
+                Confirmation::GetFileAttributes {
+                    attrs: FileAttributes::empty(),
+                    error: None,
+                },
+            ),
+            (
+                Request::SetFileAttributes {
+                    filename: b"A.TXT",
+                    attrs: FileAttributes::empty(),
+                },
+                Confirmation::SetFileAttributes { error: None },
+            ),
+            (
+                Request::GetMemoryInfo,
+                Confirmation::GetMemoryInfo {
+                    info: MemoryInfo::default(),
+                    error: Some(Error::BadHeader),
+                },
+            ),
+            (
+                Request::ScreenCapture,
+                Confirmation::ScreenCapture {
+                    chunk: b"",
+                    error: Some(Error::IoError),
+                },
+            ),
+        ];
+        let mut cw = CommandWriter::new();
+        for &(req, cfm) in pairs.iter() {
+            cw.send_request(&req).unwrap();
+            assert_eq!(cw.bytes[0], req.opcode());
+            cw.send_confirmation(&cfm).unwrap();
+            assert_eq!(cw.bytes[0], req.expected_confirmation_opcode());
+            assert!(req.accepts_confirmation(cw.bytes[0]));
+        }
+
+        // Replies which don't follow the `| 0x80` rule
+        let req = Request::ReadDir { handle: dir };
+        cw.send_confirmation(&Confirmation::ReadDirEnd).unwrap();
+        assert_ne!(cw.bytes[0], req.expected_confirmation_opcode());
+        assert!(req.accepts_confirmation(cw.bytes[0]));
+        assert!(!Request::OpenDir.accepts_confirmation(cw.bytes[0]));
+        assert!(!req.accepts_confirmation(opcodes::CLOSE_DIR_CFM));
+
+        let req = Request::SearchFiles { pattern: b"*.TXT" };
+        cw.send_confirmation(&Confirmation::SearchResultEnd)
+            .unwrap();
+        assert_ne!(cw.bytes[0], req.expected_confirmation_opcode());
+        assert!(req.accepts_confirmation(cw.bytes[0]));
+        assert!(req.accepts_confirmation(opcodes::SEARCH_RESULT_CFM));
+        assert!(!Request::ReadDir { handle: dir }.accepts_confirmation(cw.bytes[0]));
+    }
+
+    #[test]
+    fn confirmation_errors() {
+        assert_eq!(Confirmation::Ping.error(), None);
+        assert!(!Confirmation::Ping.is_error());
+        let ok = Confirmation::CloseFile { error: None };
+        assert_eq!(ok.error(), None);
+        assert!(!ok.is_error());
+        for &e in ALL_ERRORS.iter() {
+            let cfm = Confirmation::Read {
+                data: b"",
+                error: Some(e),
+            };
+            assert_eq!(cfm.error(), Some(e));
+            assert!(cfm.is_error());
+        }
+        let end = Confirmation::ReadDir {
+            entry: None,
+            error: Some(Error::FileNotFound),
+        };
+        assert!(end.is_error());
+    }
+
+    #[test]
+    fn consecutive_bad_checksums() {
+        let mut cr = CommandReader::new();
+        for _ in 0..3 {
+            for &b in &[END, PING_REQ, 0xE1, 0xF2] {
+                cr.push_byte(b).unwrap();
+            }
+            assert_eq!(cr.push_byte(END), Err(Error::BadChecksum));
+        }
+        assert_eq!(cr.consecutive_bad_checksums(), 3);
+        for &b in &[END, PING_REQ, 0xE1, 0xF1, END] {
+            cr.push_byte(b).unwrap();
+        }
+        assert_eq!(cr.consecutive_bad_checksums(), 0);
+        assert_eq!(cr.checksum_errors(), 3);
+    }
+
+    #[test]
+    fn skip_to_next_frame() {
+        let mut stream = [0u8; 105];
+        let mut lfsr = 0x5Au8;
+        for b in stream[0..100].iter_mut() {
+            *b = if lfsr == END { 0x00 } else { lfsr };
+            lfsr = (lfsr >> 1) ^ if lfsr & 1 != 0 { 0xB8 } else { 0 };
+        }
+        stream[100..].copy_from_slice(&[END, PING_REQ, 0xE1, 0xF1, END]);
+
+        let mut cr = CommandReader::new();
+        // Part way through a frame when the garbage starts
+        cr.push_byte(END).unwrap();
+        cr.push_byte(PING_REQ).unwrap();
+        assert_eq!(cr.skip_to_next_frame(&stream[0..100]), 100);
+        assert!(cr.is_synchronised());
+        let skipped = cr.skip_to_next_frame(&stream);
+        assert_eq!(skipped, 101);
+        let mut found = None;
+        for &b in &stream[skipped..] {
+            if let Some(payload) = cr.push_byte(b).unwrap() {
+                found = Some(payload.len());
+            }
+        }
+        assert_eq!(found, Some(1));
+        assert_eq!(cr.bytes_consumed(), 2 + 100 + 105);
+    }
+
+    #[test]
+    fn clone_for_retry() {
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        let mut retry = cw.clone_for_retry();
+        for _ in 0..MIN_FRAME_SIZE {
+            let b = cw.get_byte();
+            assert!(b.is_some());
+            assert_eq!(retry.get_byte(), b);
+        }
+        assert_eq!(cw.get_byte(), None);
+        assert_eq!(retry.get_byte(), None);
+
+        // A retry made part way through an escaped frame starts again
+        cw.send_read_cfm(&[END, ESC], None).unwrap();
+        let mut first = [0u8; 16];
+        for b in first.iter_mut().take(4) {
+            *b = cw.get_byte().unwrap();
+        }
+        let mut retry = cw.clone_for_retry();
+        let mut again = [0u8; 16];
+        for b in again.iter_mut().take(4) {
+            *b = retry.get_byte().unwrap();
+        }
+        assert_eq!(first, again);
+    }
+
+    #[test]
+    fn framed_bytes_iter() {
+        let payloads: [&[u8]; 4] = [
+            &[PING_REQ],
+            &[READ_CFM, 0x00, END, ESC, ESC_END, 0x41],
+            b"plain payload",
+            &[],
+        ];
+        let mut cw = CommandWriter::new();
+        for payload in payloads.iter() {
+            cw.send_raw_bytes(payload).unwrap();
+            let mut it = super::framed_bytes_iter(payload);
+            while let Some(b) = cw.get_byte() {
+                assert_eq!(it.next(), Some(b));
+            }
+            assert_eq!(it.next(), None);
+        }
+        assert_eq!(
+            cw.send_raw_bytes(&[0u8; MAX_PAYLOAD + 1]),
+            Err(Error::BufferOverflow)
+        );
+
+        fn is_send_static<T: Send + 'static>(_: &T) {}
+        static PING: [u8; 1] = [PING_REQ];
+        is_send_static(&super::framed_bytes_iter(&PING));
+    }
+
+    #[test]
+    fn keypress_batch() {
+        let text = b"The quick brown fox jumps over the lazy dog";
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+        let mut received = [0u8; 64];
+        let mut len = 0;
+        let mut remaining = &text[..];
+        let mut frames = 0;
+        while !remaining.is_empty() {
+            let used = cw.send_keypress_batch(remaining).unwrap();
+            assert!(used > 0 && used <= MAX_KEYPRESSES_PER_FRAME);
+            remaining = &remaining[used..];
+            frames += 1;
+            while let Some(b) = cw.get_byte() {
+                if let Some(payload) = cr.push_byte(b).unwrap() {
+                    match Message::parse(payload) {
+                        Ok(Message::Indication(Indication::Keypresses(keys))) => {
+                            received[len..len + keys.len()].copy_from_slice(keys);
+                            len += keys.len();
+                        }
+                        other => panic!("unexpected {:?}", other),
+                    }
+                }
+            }
+        }
+        assert_eq!(frames, 2);
+        assert_eq!(&received[0..len], &text[..]);
+        assert_eq!(cw.send_keypress_batch(b""), Ok(0));
+        assert_eq!(Message::parse(&[KEYPRESS_BATCH_IND]), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn audio_stop_indication() {
+        let reasons = [
+            (AudioStopReason::EndOfFile, [AUDIO_STOP_IND, 0x00, 0x00]),
+            (AudioStopReason::Stopped, [AUDIO_STOP_IND, 0x01, 0x00]),
+            (AudioStopReason::Error(0x12), [AUDIO_STOP_IND, 0x02, 0x12]),
+            (
+                AudioStopReason::HARDWARE_FAILURE,
+                [AUDIO_STOP_IND, 0x02, 0xFF],
+            ),
+        ];
+        let mut cw = CommandWriter::new();
+        for &(reason, wire) in reasons.iter() {
+            cw.send_audio_stop_indication(reason).unwrap();
+            assert_eq!(&cw.bytes[0..cw.count], &wire);
+            assert_eq!(parse_audio_stop_indication(&wire), Ok(reason));
+            assert_eq!(
+                Message::parse(&wire),
+                Ok(Message::Indication(Indication::AudioStop(reason)))
+            );
+        }
+        assert_eq!(
+            parse_audio_stop_indication(&[AUDIO_STOP_IND, 0x03, 0x00]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_audio_stop_indication(&[AUDIO_STOP_IND, 0x00, 0x01]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_audio_stop_indication(&[AUDIO_STOP_IND, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn interrupt_indication() {
+        let codes = [
+            (InterruptCode::DiskEjected, 0x01),
+            (InterruptCode::PowerLow, 0x02),
+            (InterruptCode::PowerCritical, 0x03),
+            (InterruptCode::Unknown(0x80), 0x80),
+        ];
+        let mut cw = CommandWriter::new();
+        for &(code, byte) in codes.iter() {
+            cw.send_interrupt_indication(code).unwrap();
+            assert_eq!(&cw.bytes[0..cw.count], &[INTERRUPT_IND, byte]);
+            assert_eq!(parse_interrupt_indication(&[INTERRUPT_IND, byte]), Ok(code));
+            assert_eq!(
+                Message::parse(&[INTERRUPT_IND, byte]),
+                Ok(Message::Indication(Indication::Interrupt(code)))
+            );
+        }
+        assert_eq!(
+            parse_interrupt_indication(&[INTERRUPT_IND]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn joystick_indication() {
+        let released = JoystickState::default();
+        let pressed = JoystickState {
+            up: true,
+            down: true,
+            left: true,
+            right: true,
+            fire: true,
+        };
+        let fire = JoystickState {
+            fire: true,
+            ..released
+        };
+        let states = [(released, 0x00), (pressed, 0x1F), (fire, 0x10)];
+        let mut cw = CommandWriter::new();
+        for &(state, byte) in states.iter() {
+            cw.send_joystick_indication(state).unwrap();
+            assert_eq!(&cw.bytes[0..cw.count], &[JOYSTICK_IND, byte]);
+            assert_eq!(parse_joystick_indication(&[JOYSTICK_IND, byte]), Ok(state));
+            assert_eq!(
+                Message::parse(&[JOYSTICK_IND, byte]),
+                Ok(Message::Indication(Indication::Joystick(state)))
+            );
+        }
+        for &reserved in &[0x20, 0x40, 0x80, 0x3F] {
+            assert_eq!(
+                parse_joystick_indication(&[JOYSTICK_IND, reserved]),
+                Err(Error::BadHeader)
+            );
+        }
+        assert_eq!(Message::parse(&[JOYSTICK_IND, 0x20]), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn screen_resize_smallest() {
+        let mut cw = CommandWriter::new();
+        cw.send_screen_resize_indication(MIN_SCREEN_COLS, MIN_SCREEN_ROWS)
+            .unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[SCREEN_RESIZE_IND, 1, 1]);
+        let ind = ScreenResizeIndication { cols: 1, rows: 1 };
+        assert_eq!(
+            parse_screen_resize_indication(&cw.bytes[0..cw.count]),
+            Ok(ind)
+        );
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Indication(Indication::ScreenResize(ind)))
+        );
+        cw.send_screen_resize_indication(MAX_SCREEN_COLS, MAX_SCREEN_ROWS)
+            .unwrap();
+        assert_eq!(
+            parse_screen_resize_indication(&cw.bytes[0..cw.count]),
+            Ok(ScreenResizeIndication {
+                cols: 255,
+                rows: 255,
+            })
+        );
+    }
+
+    #[test]
+    fn screen_resize_empty() {
+        let mut cw = CommandWriter::new();
+        assert_eq!(
+            cw.send_screen_resize_indication(0, 40),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            cw.send_screen_resize_indication(48, 0),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_screen_resize_indication(&[SCREEN_RESIZE_IND, 0, 40]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            Message::parse(&[SCREEN_RESIZE_IND, 48, 0]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_screen_resize_indication(&[SCREEN_RESIZE_IND, 48]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn mouse_largest_deltas() {
+        let mut cw = CommandWriter::new();
+        let ind = MouseIndication {
+            delta_x: 127,
+            delta_y: 127,
+            buttons: MouseButtons::empty(),
+        };
+        cw.send_mouse_indication(ind).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[MOUSE_IND, 0x7F, 0x7F, 0x00]);
+        assert_eq!(parse_mouse_indication(&cw.bytes[0..cw.count]), Ok(ind));
+
+        let ind = MouseIndication {
+            delta_x: -128,
+            delta_y: -128,
+            buttons: MouseButtons::LEFT,
+        };
+        cw.send_mouse_indication(ind).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[MOUSE_IND, 0x80, 0x80, 0x01]);
+        assert_eq!(parse_mouse_indication(&cw.bytes[0..cw.count]), Ok(ind));
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Indication(Indication::Mouse(ind)))
+        );
+
+        for (dx, dy) in [(128, 0), (0, -129)] {
+            let ind = MouseIndication {
+                delta_x: dx,
+                delta_y: dy,
+                buttons: MouseButtons::empty(),
+            };
+            assert_eq!(cw.send_mouse_indication(ind), Err(Error::BadHeader));
+        }
+    }
+
+    #[test]
+    fn mouse_buttons() {
+        let all = MouseButtons::LEFT | MouseButtons::RIGHT | MouseButtons::MIDDLE;
+        assert_eq!(all.to_wire(), 0x07);
+        assert!(all.contains(MouseButtons::MIDDLE));
+        let mut buttons = all;
+        buttons.remove(MouseButtons::RIGHT);
+        assert!(!buttons.contains(MouseButtons::RIGHT));
+        buttons.insert(MouseButtons::RIGHT);
+        assert_eq!(buttons, all);
+        assert_eq!(
+            parse_mouse_indication(&[MOUSE_IND, 0xFF, 0x01, 0x07]),
+            Ok(MouseIndication {
+                delta_x: -1,
+                delta_y: 1,
+                buttons: all,
+            })
+        );
+        assert_eq!(
+            parse_mouse_indication(&[MOUSE_IND, 0, 0, 0x08]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            Message::parse(&[MOUSE_IND, 0, 0, 0x08]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_mouse_indication(&[MOUSE_IND, 0, 0]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn battery_charging_full() {
+        let ind = BatteryIndication {
+            percent: 100,
+            millivolts: 4200,
+            charging: true,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_battery_indication(ind).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[BATTERY_IND, 100, 0x10, 0x68, 0x01]
+        );
+        assert_eq!(parse_battery_indication(&cw.bytes[0..cw.count]), Ok(ind));
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Indication(Indication::Battery(ind)))
+        );
+    }
+
+    #[test]
+    fn battery_discharging_low() {
+        let ind = BatteryIndication {
+            percent: 5,
+            millivolts: BatteryIndication::MIN_MILLIVOLTS,
+            charging: false,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_battery_indication(ind).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[BATTERY_IND, 5, 0x0B, 0xB8, 0x00]);
+        assert_eq!(parse_battery_indication(&cw.bytes[0..cw.count]), Ok(ind));
+        // Reserved flags are ignored
+        assert_eq!(
+            parse_battery_indication(&[BATTERY_IND, 5, 0x0B, 0xB8, 0x80]),
+            Ok(ind)
+        );
+    }
+
+    #[test]
+    fn battery_out_of_range() {
+        assert_eq!(
+            parse_battery_indication(&[BATTERY_IND, 101, 0x10, 0x68, 0x00]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            Message::parse(&[BATTERY_IND, 101, 0x10, 0x68, 0x00]),
+            Err(Error::BadHeader)
+        );
+        // 2999 mV
+        assert_eq!(
+            parse_battery_indication(&[BATTERY_IND, 50, 0x0B, 0xB7, 0x00]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_battery_indication(&[BATTERY_IND, 50, 0x0B, 0xB8]),
+            Err(Error::BadHeader)
+        );
+        let mut cw = CommandWriter::new();
+        let ind = BatteryIndication {
+            percent: 101,
+            millivolts: 4200,
+            charging: false,
+        };
+        assert_eq!(cw.send_battery_indication(ind), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn wire_constants() {
+        assert_eq!(Error::BadChecksum.to_wire(), wire::ERROR_BAD_CHECKSUM);
+        assert_eq!(Error::BadHeader.to_wire(), wire::ERROR_BAD_HEADER);
+        assert_eq!(Error::BufferOverflow.to_wire(), wire::ERROR_BUFFER_OVERFLOW);
+        assert_eq!(Error::FileNotFound.to_wire(), wire::ERROR_FILE_NOT_FOUND);
+        assert_eq!(Error::BadOffset.to_wire(), wire::ERROR_BAD_OFFSET);
+        assert_eq!(Error::IoError.to_wire(), wire::ERROR_IO_ERROR);
+        assert_eq!(
+            Error::PermissionDenied.to_wire(),
+            wire::ERROR_PERMISSION_DENIED
+        );
+        assert_eq!(Error::AlreadyExists.to_wire(), wire::ERROR_ALREADY_EXISTS);
+        assert_eq!(Error::ExecFormat.to_wire(), wire::ERROR_EXEC_FORMAT);
+        assert_eq!(Error::Locked.to_wire(), wire::ERROR_LOCKED);
+        assert_eq!(Error::InvalidHandle.to_wire(), wire::ERROR_INVALID_HANDLE);
+        assert_eq!(Error::from_wire(wire::ERROR_SUCCESS), None);
+        assert_eq!(wire::PING_REQ, opcodes::PING_REQ);
+        assert_eq!(wire::KEYPRESS_IND, opcodes::KEYPRESS_IND);
+    }
+
+    #[test]
+    fn assert_opcode() {
+        let mut cr = CommandReader::new();
+        assert_eq!(
+            cr.assert_opcode(CLOSE_FILE_CFM),
+            Err(ProtocolError::NoFrame)
+        );
+        let mut cw = CommandWriter::new();
+        cw.send_close_cfm(None).unwrap();
+        while let Some(b) = cw.get_byte() {
+            cr.push_byte(b).unwrap();
+        }
+        assert_eq!(
+            cr.assert_opcode(CLOSE_FILE_CFM),
+            Ok(&[CLOSE_FILE_CFM, 0x00][..])
+        );
+        // Already taken
+        assert_eq!(
+            cr.assert_opcode(CLOSE_FILE_CFM),
+            Err(ProtocolError::NoFrame)
+        );
+
+        cw.send_open_cfm(Some(Handle::new(1)), None).unwrap();
+        while let Some(b) = cw.get_byte() {
+            cr.push_byte(b).unwrap();
+        }
+        assert_eq!(
+            cr.assert_opcode(CLOSE_FILE_CFM),
+            Err(ProtocolError::UnknownOpcode(OPEN_FILE_CFM))
+        );
+
+        // A frame is only available until the next byte arrives
+        cw.send_close_cfm(None).unwrap();
+        while let Some(b) = cw.get_byte() {
+            cr.push_byte(b).unwrap();
+        }
+        cr.push_byte(0x00).unwrap();
+        assert_eq!(
+            cr.assert_opcode(CLOSE_FILE_CFM),
+            Err(ProtocolError::NoFrame)
+        );
+    }
+
+    #[test]
+    fn read_cfm_v2() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_cfm_with_offset(0x1234_5678, b"data", None)
+            .unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[
+                READ_CFM_V2,
+                0x00,
+                0x12,
+                0x34,
+                0x56,
+                0x78,
+                b'd',
+                b'a',
+                b't',
+                b'a'
+            ]
+        );
+        assert_eq!(
+            parse_read_cfm_v2(&cw.bytes[0..cw.count]),
+            Ok((0x1234_5678, &b"data"[..]))
+        );
+        cw.send_read_cfm_with_offset(16, &[], None).unwrap();
+        assert_eq!(parse_read_cfm_v2(&cw.bytes[0..cw.count]), Ok((16, &[][..])));
+        cw.send_read_cfm_with_offset(16, &[], Some(Error::BadOffset))
+            .unwrap();
+        assert_eq!(
+            parse_read_cfm_v2(&cw.bytes[0..cw.count]),
+            Err(Error::BadOffset)
+        );
+        assert_eq!(
+            parse_read_cfm_v2(&[READ_CFM_V2, 0x00, 0x00]),
+            Err(Error::BadHeader)
+        );
+        // A v1 confirmation is not a v2 one
+        cw.send_read_cfm(b"data", None).unwrap();
+        assert_eq!(
+            parse_read_cfm_v2(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn open_file_req_v2() {
+        let mut cw = CommandWriter::new();
+        cw.send_open_file_req_v2(b"A.TXT", Mode::Write, 0x0001_2345)
+            .unwrap();
+        let mut frame = [0u8; 16];
+        for b in frame.iter_mut() {
+            *b = cw.get_byte().unwrap_or(0);
+        }
+        // END, opcode, 5 bytes of filename, null, mode, size hint
+        assert_eq!(frame[1], OPEN_FILE_REQ_V2);
+        assert_eq!(frame[8], Mode::Write.wire_byte());
+        assert_eq!(&frame[9..13], &[0x00, 0x01, 0x23, 0x45]);
+        assert_eq!(
+            cw.send_open_file_req_v2(b"A\0", Mode::Write, 0),
+            Err(Error::BadHeader)
+        );
+        cw.send_open_cfm_v2(Some(Handle::new(2)), None).unwrap();
+        assert_eq!(parse_open_cfm(&cw.bytes[0..cw.count]), Ok(Handle::new(2)));
+    }
+
+    #[test]
+    fn read_req_v2() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_req_v2(Handle::new(1), 0x10, 0x0102).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[READ_REQ_V2, 0x01, 0x00, 0x00, 0x00, 0x10, 0x01, 0x02]
+        );
+        assert_eq!(
+            cw.send_read_req_v2(Handle::new(1), 0x10, 0),
+            Err(Error::BadOffset)
+        );
+        assert_eq!(
+            Message::parse(&[READ_REQ_V2, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn checksum_cfm_v2() {
+        assert_eq!(
+            ChecksumAlgorithm::Adler32.compute(b"Wikipedia"),
+            Some(0x11E6_0398)
+        );
+        assert_eq!(ChecksumAlgorithm::Adler32.compute(b""), Some(1));
+        assert_eq!(ChecksumAlgorithm::Unknown(0xFF).compute(b""), None);
+        let mut cw = CommandWriter::new();
+        let algorithms = [
+            ChecksumAlgorithm::Crc32Iso,
+            ChecksumAlgorithm::Adler32,
+            ChecksumAlgorithm::Unknown(0xFF),
+        ];
+        for &algorithm in algorithms.iter() {
+            cw.send_checksum_cfm_v2(algorithm, 0xCBF4_3926, None)
+                .unwrap();
+            assert_eq!(
+                parse_checksum_cfm_v2(&cw.bytes[0..cw.count]),
+                Ok((algorithm, 0xCBF4_3926))
+            );
+        }
+        assert_eq!(cw.bytes[2], 0xFF);
+        cw.send_checksum_cfm_v2(ChecksumAlgorithm::Crc32Iso, 0, Some(Error::FileNotFound))
+            .unwrap();
+        assert_eq!(
+            parse_checksum_cfm_v2(&cw.bytes[0..cw.count]),
+            Err(Error::FileNotFound)
+        );
+        cw.send_checksum_cfm(0, None).unwrap();
+        assert_eq!(
+            parse_checksum_cfm_v2(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn frame_length_hint() {
+        let mut cr = CommandReader::new();
+        assert_eq!(cr.frame_length_hint(), None);
+        cr.push_byte(END).unwrap();
+        assert_eq!(cr.frame_length_hint(), None);
+        cr.push_byte(PING_REQ).unwrap();
+        assert_eq!(cr.frame_length_hint(), Some(5));
+        cr.force_resync();
+        cr.push_byte(END).unwrap();
+        cr.push_byte(OPEN_FILE_REQ).unwrap();
+        assert_eq!(cr.frame_length_hint(), None);
+
+        // Check the hints against real frames
+        let mut cw = CommandWriter::new();
+        let frames: [fn(&mut CommandWriter); 4] = [
+            |cw| cw.send_close_all_cfm(3, None).unwrap(),
+            |cw| cw.send_read_req(Handle::new(1), 2).unwrap(),
+            |cw| cw.send_checksum_cfm_v2(FILE_CHECKSUM, 1, None).unwrap(),
+            |cw| cw.send_read_req_v2(Handle::new(1), 2, 3).unwrap(),
+        ];
+        for build in frames.iter() {
+            build(&mut cw);
+            let mut cr = CommandReader::new();
+            cr.push_byte(cw.get_byte().unwrap()).unwrap();
+            cr.push_byte(cw.get_byte().unwrap()).unwrap();
+            assert_eq!(cr.frame_length_hint(), Some(cw.count + MIN_FRAME_OVERHEAD));
+        }
+    }
+
+    #[test]
+    fn writer_equality() {
+        let mut a = CommandWriter::new();
+        a.send_ping_req();
+        let mut b = CommandWriter::new();
+        b.send_ping_req();
+        assert_eq!(a, b);
+        // Sending doesn't change what the writer holds
+        while a.get_byte().is_some() {}
+        assert_eq!(a, b);
+        let mut c = CommandWriter::new();
+        c.send_ping_cfm();
+        assert_ne!(a, c);
+        c.send_read_cfm(&[], None).unwrap();
+        b.send_read_cfm(&[0], None).unwrap();
+        assert_ne!(b, c);
+    }
+
+    #[test]
+    fn delete_and_rename_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_delete_cfm(None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[DELETE_FILE_CFM, 0x00]);
+        assert_eq!(parse_delete_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        cw.send_rename_cfm(None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[RENAME_FILE_CFM, 0x00]);
+        assert_eq!(parse_rename_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        let errors = [
+            Error::PermissionDenied,
+            Error::AlreadyExists,
+            Error::FileNotFound,
+        ];
+        for &e in errors.iter() {
+            cw.send_delete_cfm(Some(e)).unwrap();
+            assert_eq!(parse_delete_cfm(&cw.bytes[0..cw.count]), Err(e));
+            cw.send_rename_cfm(Some(e)).unwrap();
+            assert_eq!(parse_rename_cfm(&cw.bytes[0..cw.count]), Err(e));
+        }
+        assert_eq!(
+            parse_delete_cfm(&[RENAME_FILE_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+        let mut general = CommandWriter::new();
+        general.send_delete_cfm(Some(Error::FileNotFound)).unwrap();
+        cw.send_delete_cfm_not_found().unwrap();
+        assert_eq!(cw, general);
+    }
+
+    #[test]
+    fn delete_and_rename_req() {
+        let mut cw = CommandWriter::new();
+        cw.send_rename_file_req(b"A", b"B").unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], b"\x12A\x00B\x00");
+        assert_eq!(cw.send_rename_file_req(b"A", b"B\0"), Err(Error::BadHeader));
+        assert_eq!(cw.send_delete_file_req(b"A\0"), Err(Error::BadHeader));
+        assert_eq!(Message::parse(b"\x12A\x00B"), Err(Error::BadHeader));
+        assert_eq!(Message::parse(b"\x11A\x00B"), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn make_and_change_dir_cfm() {
+        let mut success = CommandWriter::new();
+        success.send_make_dir_cfm(None).unwrap();
+        assert_eq!(&success.bytes[0..success.count], &[MAKE_DIR_CFM, 0x00]);
+        assert_eq!(parse_make_dir_cfm(&success.bytes[0..success.count]), Ok(()));
+        let mut exists = CommandWriter::new();
+        exists
+            .send_make_dir_cfm(Some(Error::AlreadyExists))
+            .unwrap();
+        assert_eq!(&exists.bytes[0..exists.count], &[MAKE_DIR_CFM, 0x08]);
+        assert_eq!(
+            parse_make_dir_cfm(&exists.bytes[0..exists.count]),
+            Err(Error::AlreadyExists)
+        );
+        let mut not_found = CommandWriter::new();
+        not_found
+            .send_change_dir_cfm(Some(Error::FileNotFound))
+            .unwrap();
+        assert_eq!(
+            &not_found.bytes[0..not_found.count],
+            &[CHANGE_DIR_CFM, 0x04]
+        );
+        assert_eq!(
+            parse_change_dir_cfm(&not_found.bytes[0..not_found.count]),
+            Err(Error::FileNotFound)
+        );
+        assert_ne!(exists, not_found);
+        assert_ne!(exists, success);
+        assert_ne!(not_found, success);
+        assert_eq!(
+            parse_make_dir_cfm(&[CHANGE_DIR_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(
+            parse_change_dir_cfm(&[MAKE_DIR_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+        assert_eq!(success.send_make_dir_req(b"A\0"), Err(Error::BadHeader));
+        assert_eq!(success.send_change_dir_req(b"A\0"), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn lock_and_unlock_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_lock_file_cfm(None).unwrap();
+        assert_eq!(parse_lock_file_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        cw.send_lock_file_cfm(Some(Error::Locked)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[LOCK_FILE_CFM, 0x0A]);
+        assert_eq!(
+            parse_lock_file_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::Locked)
+        );
+        cw.send_unlock_file_cfm(None).unwrap();
+        assert_eq!(parse_unlock_file_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        cw.send_unlock_file_cfm(Some(Error::InvalidHandle)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[UNLOCK_FILE_CFM, 0x0B]);
+        assert_eq!(
+            parse_unlock_file_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::InvalidHandle)
+        );
+        assert_eq!(
+            parse_lock_file_cfm(&[UNLOCK_FILE_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+
+        let opcodes = [
+            LOCK_FILE_REQ,
+            UNLOCK_FILE_REQ,
+            LOCK_FILE_CFM,
+            UNLOCK_FILE_CFM,
+        ];
+        for (i, a) in opcodes.iter().enumerate() {
+            for b in opcodes[i + 1..].iter() {
+                assert_ne!(a, b);
+            }
+        }
+        for &req in &opcodes[0..2] {
+            assert_eq!(opcode_direction(req), OpcodeDirection::Request);
+        }
+        for &cfm in &opcodes[2..4] {
+            assert_eq!(opcode_direction(cfm), OpcodeDirection::Confirmation);
+        }
+    }
+
+    #[test]
+    fn execute_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_execute_cfm(0, None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[EXECUTE_FILE_CFM, 0x00, 0x00]);
+        assert_eq!(parse_execute_cfm(&cw.bytes[0..cw.count]), Ok(0));
+        cw.send_execute_cfm(-1, None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[EXECUTE_FILE_CFM, 0x00, 0xFF]);
+        assert_eq!(parse_execute_cfm(&cw.bytes[0..cw.count]), Ok(-1));
+        cw.send_execute_cfm(-1, Some(Error::ExecFormat)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[EXECUTE_FILE_CFM, 0x09, 0x00]);
+        assert_eq!(
+            parse_execute_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::ExecFormat)
+        );
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Confirmation(Confirmation::ExecuteFile {
+                exit_code: 0,
+                error: Some(Error::ExecFormat),
+            }))
+        );
+        assert_eq!(
+            parse_execute_cfm(&[EXECUTE_FILE_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn get_working_dir_root() {
+        let mut cw = CommandWriter::new();
+        cw.send_get_working_dir_cfm(b"/", None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], b"\x98\x00/\x00");
+        assert_eq!(
+            parse_get_working_dir_cfm(&cw.bytes[0..cw.count]),
+            Ok(&b"/"[..])
+        );
+        cw.send_get_working_dir_cfm(b"", Some(Error::IoError))
+            .unwrap();
+        assert_eq!(
+            parse_get_working_dir_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::IoError)
+        );
+        assert_eq!(
+            parse_get_working_dir_cfm(b"\x98\x00/"),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn get_working_dir_max_len() {
+        let mut cw = CommandWriter::new();
+        let longest = [b'A'; MAX_PATH_LEN];
+        cw.send_get_working_dir_cfm(&longest, None).unwrap();
+        assert_eq!(cw.count, MAX_PAYLOAD);
+        assert_eq!(
+            parse_get_working_dir_cfm(&cw.bytes[0..cw.count]),
+            Ok(&longest[..])
+        );
+        let too_long = [b'A'; MAX_PATH_LEN + 1];
+        assert_eq!(
+            cw.send_get_working_dir_cfm(&too_long, None),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(
+            cw.send_get_working_dir_cfm(b"/A\0", None),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn volume_label_cfm() {
+        let labels = [&b"           "[..], b"", b"MONOTRON"];
+        let mut cw = CommandWriter::new();
+        for &label in labels.iter() {
+            cw.send_get_volume_label_cfm(label, None).unwrap();
+            assert_eq!(cw.count, label.len() + 3);
+            assert_eq!(
+                parse_get_volume_label_cfm(&cw.bytes[0..cw.count]),
+                Ok(label)
+            );
+        }
+        cw.send_get_volume_label_cfm(b"", Some(Error::IoError))
+            .unwrap();
+        assert_eq!(
+            parse_get_volume_label_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::IoError)
+        );
+        cw.send_set_volume_label_cfm(Some(Error::PermissionDenied))
+            .unwrap();
+        assert_eq!(
+            parse_set_volume_label_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn volume_label_max_len() {
+        let mut cw = CommandWriter::new();
+        let longest = [b'V'; MAX_VOLUME_LABEL_LEN];
+        assert_eq!(longest.len(), 11);
+        cw.send_get_volume_label_cfm(&longest, None).unwrap();
+        assert_eq!(
+            parse_get_volume_label_cfm(&cw.bytes[0..cw.count]),
+            Ok(&longest[..])
+        );
+        cw.send_set_volume_label_req(&longest).unwrap();
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Request(Request::SetVolumeLabel {
+                label: &longest
+            }))
+        );
+        let too_long = [b'V'; MAX_VOLUME_LABEL_LEN + 1];
+        assert_eq!(
+            cw.send_get_volume_label_cfm(&too_long, None),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(
+            cw.send_set_volume_label_req(&too_long),
+            Err(Error::BufferOverflow)
+        );
+        assert_eq!(cw.send_set_volume_label_req(b"A\0"), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn memory_info_all_free() {
+        let info = MemoryInfo {
+            total_ram: 32 * 1024,
+            free_ram: 32 * 1024,
+            stack_usage: 0,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_get_memory_info_cfm(info, None).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            b"\xA2\x00\x00\x00\x80\x00\x00\x00\x80\x00\x00\x00\x00\x00"
+        );
+        assert_eq!(fixed_payload_len(GET_MEMORY_INFO_CFM), Some(cw.count));
+        assert_eq!(parse_get_memory_info_cfm(&cw.bytes[0..cw.count]), Ok(info));
+    }
+
+    #[test]
+    fn memory_info_stack_overflow() {
+        let info = MemoryInfo {
+            total_ram: 32 * 1024,
+            free_ram: 0,
+            stack_usage: 32 * 1024,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_get_memory_info_cfm(info, None).unwrap();
+        assert_eq!(parse_get_memory_info_cfm(&cw.bytes[0..cw.count]), Ok(info));
+
+        let too_much_stack = MemoryInfo {
+            stack_usage: 32 * 1024 + 1,
+            ..info
+        };
+        assert_eq!(
+            cw.send_get_memory_info_cfm(too_much_stack, None),
+            Err(Error::BadHeader)
+        );
+        let mut payload = [0u8; 14];
+        payload[0] = GET_MEMORY_INFO_CFM;
+        payload[2..6].copy_from_slice(&0x8000u32.to_be_bytes());
+        payload[10..14].copy_from_slice(&0x8001u32.to_be_bytes());
+        assert_eq!(parse_get_memory_info_cfm(&payload), Err(Error::BadHeader));
+        assert_eq!(Message::parse(&payload), Err(Error::BadHeader));
+        // Too much free RAM
+        payload[6..10].copy_from_slice(&0x8001u32.to_be_bytes());
+        payload[10..14].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(parse_get_memory_info_cfm(&payload), Err(Error::BadHeader));
+        assert_eq!(
+            parse_get_memory_info_cfm(&payload[0..13]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn get_version_cfm() {
+        let device = ProtocolVersion {
+            major: 0,
+            minor: 2,
+            patch: 7,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_get_version_cfm(device, None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[GET_VERSION_CFM, 0x00, 0, 2, 7]);
+        let parsed = parse_get_version_cfm(&cw.bytes[0..cw.count]).unwrap();
+        assert_eq!(parsed, device);
+        let host = ProtocolVersion {
+            major: 0,
+            minor: 2,
+            patch: 0,
+        };
+        assert!(parsed.is_compatible_with(&host));
+        assert!(host.is_compatible_with(&parsed));
+        let new = ProtocolVersion {
+            major: 1,
+            minor: 0,
+            patch: 0,
+        };
+        assert!(!new.is_compatible_with(&host));
+        assert!(!host.is_compatible_with(&new));
+        // Same major version, but older than MIN_COMPATIBLE_REVISION
+        let old = ProtocolVersion {
+            major: 0,
+            minor: 0,
+            patch: 9,
+        };
+        assert!(!ProtocolVersion::CURRENT.is_compatible_with(&old));
+        assert!(!old.is_compatible_with(&ProtocolVersion::CURRENT));
+        // The method and the free function agree
+        for (version, revision) in [(old, "0.0.9"), (host, "0.2.0"), (new, "1.0.0")] {
+            assert_eq!(
+                ProtocolVersion::CURRENT.is_compatible_with(&version),
+                super::is_compatible_with(revision)
+            );
+        }
+        cw.send_get_version_cfm(device, Some(Error::BadHeader))
+            .unwrap();
+        assert_eq!(
+            parse_get_version_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+        let v = ProtocolVersion::CURRENT;
+        assert_eq!(
+            parse_revision(PROTOCOL_REVISION),
+            Some((v.major.into(), v.minor.into(), v.patch.into()))
+        );
+    }
+
+    #[test]
+    fn error_conversions() {
+        for &e in ALL_ERRORS.iter() {
+            let byte = u8::from(e);
+            assert_eq!(byte, e.to_wire());
+            assert_eq!(Error::try_from(byte), Ok(e));
+            assert_eq!(fmt::Error::from(e), fmt::Error);
+        }
+        assert_eq!(Error::try_from(0x00), Err(()));
+        assert_eq!(Error::try_from(0xFF), Err(()));
+    }
+
+    #[test]
+    fn close_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_close_cfm(None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[CLOSE_FILE_CFM, 0x00]);
+        assert_eq!(parse_close_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        for &e in ALL_ERRORS.iter() {
+            cw.send_close_cfm(Some(e)).unwrap();
+            assert_eq!(cw.count, 2);
+            assert_eq!(parse_close_cfm(&cw.bytes[0..cw.count]), Err(e));
+            assert_eq!(
+                parse_close_dir_cfm(&cw.bytes[0..cw.count]),
+                Err(Error::BadHeader)
+            );
+        }
+    }
+
+    #[test]
+    fn close_dir_cfm() {
+        let mut cw = CommandWriter::new();
+        cw.send_close_dir_cfm(None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[CLOSE_DIR_CFM, 0x00]);
+        assert_eq!(parse_close_dir_cfm(&cw.bytes[0..cw.count]), Ok(()));
+        for &e in ALL_ERRORS.iter() {
+            cw.send_close_dir_cfm(Some(e)).unwrap();
+            assert_eq!(cw.count, 2);
+            assert_eq!(parse_close_dir_cfm(&cw.bytes[0..cw.count]), Err(e));
+            assert_eq!(
+                parse_close_cfm(&cw.bytes[0..cw.count]),
+                Err(Error::BadHeader)
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_req() {
+        let mut cw = CommandWriter::new();
+        cw.send_checksum_req(Handle::new(9)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[0x07, 0x09]);
+    }
+
+    #[test]
+    fn checksum_cfm() {
+        // Check values from https://crccalc.com/
+        assert_eq!(FILE_CHECKSUM.compute(b"123456789"), Some(0xCBF4_3926));
+        assert_eq!(FILE_CHECKSUM.compute(b""), Some(0x0000_0000));
+        let mut cw = CommandWriter::new();
+        for &checksum in &[0xCBF4_3926, 0x0000_0000, 0xFFFF_FFFF] {
+            cw.send_checksum_cfm(checksum, None).unwrap();
+            assert_eq!(parse_checksum_cfm(&cw.bytes[0..cw.count]), Ok(checksum));
+        }
+        cw.send_checksum_cfm(0xCBF4_3926, None).unwrap();
+        assert_eq!(
+            &cw.bytes[0..cw.count],
+            &[CHECKSUM_CFM, 0x00, 0xCB, 0xF4, 0x39, 0x26]
+        );
+        cw.send_checksum_cfm(0, Some(Error::BadChecksum)).unwrap();
+        assert_eq!(
+            parse_checksum_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadChecksum)
+        );
+        assert_eq!(
+            parse_checksum_cfm(&[CHECKSUM_CFM, 0x00, 0xCB]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    const MTIME: Timestamp = Timestamp {
+        year_from_1970: 48,
+        month: 7,
+        days: 4,
+        hours: 13,
+        minutes: 45,
+        seconds: 30,
+    };
+
+    struct MockRtc {
+        fixed: Timestamp,
+    }
+
+    impl RtcSource for MockRtc {
+        fn now(&self) -> Timestamp {
+            self.fixed
+        }
+    }
+
+    #[test]
+    fn timestamp_from_rtc() {
+        let rtc = MockRtc { fixed: MTIME };
+        assert_eq!(Timestamp::from_rtc(&rtc), MTIME);
+    }
+
+    #[test]
+    fn fat_epoch() {
+        assert_eq!(Timestamp::FAT_EPOCH.to_fat_date_time(), (0x0021, 0x0000));
+        assert_eq!(
+            Timestamp::from_fat_date_time(0x0021, 0x0000),
+            Ok(Timestamp::FAT_EPOCH)
+        );
+        // Too early for FAT
+        let ts = Timestamp {
+            year_from_1970: 0,
+            ..Timestamp::FAT_EPOCH
+        };
+        assert_eq!(ts.to_fat_date_time(), (0x0021, 0x0000));
+    }
+
+    #[test]
+    fn fat_midnight() {
+        let midnight = Timestamp {
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+            ..MTIME
+        };
+        let (date, time) = midnight.to_fat_date_time();
+        assert_eq!((date, time), ((38 << 9) | (7 << 5) | 4, 0));
+        assert_eq!(Timestamp::from_fat_date_time(date, time), Ok(midnight));
+        let (date, time) = MTIME.to_fat_date_time();
+        assert_eq!(time, (13 << 11) | (45 << 5) | 15);
+        assert_eq!(Timestamp::from_fat_date_time(date, time), Ok(MTIME));
+    }
+
+    #[test]
+    fn fat_max() {
+        assert_eq!(Timestamp::FAT_MAX.to_fat_date_time(), (0xFF9F, 0xBF7D));
+        assert_eq!(
+            Timestamp::from_fat_date_time(0xFF9F, 0xBF7D),
+            Ok(Timestamp::FAT_MAX)
+        );
+        let ts = Timestamp {
+            year_from_1970: 255,
+            seconds: 59,
+            ..Timestamp::FAT_MAX
+        };
+        assert_eq!(ts.to_fat_date_time(), (0xFF9F, 0xBF7D));
+    }
+
+    #[test]
+    fn fat_fields_clamped() {
+        let ts = Timestamp {
+            month: 16,
+            days: 32,
+            minutes: 64,
+            ..Timestamp::FAT_EPOCH
+        };
+        let (date, time) = ts.to_fat_date_time();
+        assert_eq!((date, time), ((12 << 5) | 31, 59 << 5));
+        assert_eq!(
+            Timestamp::from_fat_date_time(date, time),
+            Ok(Timestamp {
+                month: 12,
+                days: 31,
+                minutes: 59,
+                ..Timestamp::FAT_EPOCH
+            })
+        );
+        let ts = Timestamp {
+            month: 0,
+            days: 0,
+            hours: 24,
+            seconds: 255,
+            ..Timestamp::FAT_EPOCH
+        };
+        assert_eq!(ts.to_fat_date_time(), ((1 << 5) | 1, (23 << 11) | 29));
+    }
+
+    #[test]
+    fn fat_out_of_range() {
+        // Month 13
+        assert_eq!(
+            Timestamp::from_fat_date_time(0x01A1, 0x0000),
+            Err(Error::BadHeader)
+        );
+        // Day 0
+        assert_eq!(
+            Timestamp::from_fat_date_time(0x0020, 0x0000),
+            Err(Error::BadHeader)
+        );
+        // 24:00:00
+        assert_eq!(
+            Timestamp::from_fat_date_time(0x0021, 24 << 11),
+            Err(Error::BadHeader)
+        );
+        // 60 seconds
+        assert_eq!(
+            Timestamp::from_fat_date_time(0x0021, 30),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn read_dir_cfm_file() {
+        let payload = b"\x8A\x00A.TXT\x00\x00\x00\x01\x02\x30\x07\x04\x0D\x2D\x1E\x00";
+        assert_eq!(
+            parse_read_dir_cfm(&payload[..]),
+            Ok(Some(DirEntry {
+                name: b"A.TXT",
+                size: 0x0102,
+                mtime: MTIME,
+                file_type: FileType::File,
+            }))
+        );
+    }
+
+    #[test]
+    fn read_dir_cfm_directory() {
+        let payload = b"\x8A\x00DOCS\x00\x00\x00\x00\x00\x30\x07\x04\x0D\x2D\x1E\x01";
+        let entry = parse_read_dir_cfm(&payload[..]).unwrap().unwrap();
+        assert_eq!(entry.name, b"DOCS");
+        assert_eq!(entry.size, 0);
+        assert_eq!(entry.mtime, MTIME);
+        assert_eq!(entry.file_type, FileType::Directory);
+    }
+
+    #[test]
+    fn read_dir_cfm_end() {
+        assert_eq!(parse_read_dir_cfm(&[READ_DIR_CFM, 0x04]), Ok(None));
+        assert_eq!(
+            parse_read_dir_cfm(&[READ_DIR_CFM, 0x05]),
+            Err(Error::BadOffset)
+        );
+        assert_eq!(
+            Message::parse(&[READ_DIR_CFM, 0x04]),
+            Ok(Message::Confirmation(Confirmation::ReadDir {
+                entry: None,
+                error: Some(Error::FileNotFound),
+            }))
+        );
+    }
+
+    #[test]
+    fn search_result_end() {
+        let mut cw = CommandWriter::new();
+        cw.send_search_result_cfm_end().unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[SEARCH_RESULT_END_CFM]);
+        assert_eq!(parse_search_result_cfm(&cw.bytes[0..cw.count]), Ok(None));
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Confirmation(Confirmation::SearchResultEnd))
+        );
+        assert_eq!(
+            opcode_direction(SEARCH_RESULT_END_CFM),
+            OpcodeDirection::Confirmation
+        );
+        let mut end = CommandWriter::new();
+        end.send_search_result_cfm(None, None).unwrap();
+        assert_eq!(end, cw);
+        assert_eq!(
+            parse_search_result_cfm(&[SEARCH_RESULT_END_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn read_dir_end_styles() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_dir_cfm_end().unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[READ_DIR_END_CFM]);
+        assert_eq!(parse_read_dir_cfm(&cw.bytes[0..cw.count]), Ok(None));
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Confirmation(Confirmation::ReadDirEnd))
+        );
+        assert_eq!(
+            opcode_direction(READ_DIR_END_CFM),
+            OpcodeDirection::Confirmation
+        );
+        // The older style
+        cw.send_read_dir_cfm(None, None).unwrap();
+        assert_eq!(parse_read_dir_cfm(&cw.bytes[0..cw.count]), Ok(None));
+        assert_eq!(
+            parse_read_dir_cfm(&[READ_DIR_END_CFM, 0x00]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn read_dir_cfm_round_trip() {
+        let entry = DirEntry {
+            name: b"LONGNAME.BAS",
+            size: 0x00C0_FFEE,
+            mtime: MTIME,
+            file_type: FileType::File,
+        };
+        let mut cw = CommandWriter::new();
+        cw.send_read_dir_cfm(Some(&entry), None).unwrap();
+        let mut cr = CommandReader::new();
+        let mut found = false;
+        while let Some(b) = cw.get_byte() {
+            if let Some(payload) = cr.push_byte(b).unwrap() {
+                let parsed = parse_read_dir_cfm(payload).unwrap().unwrap();
+                assert_eq!(parsed.name, entry.name);
+                assert_eq!(parsed.size, entry.size);
+                assert_eq!(parsed.mtime, entry.mtime);
+                assert_eq!(parsed.file_type, entry.file_type);
+                found = true;
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn read_dir_cfm_send_end() {
+        let mut cw = CommandWriter::new();
+        cw.send_read_dir_cfm(None, None).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[READ_DIR_CFM, 0x04]);
+        assert_eq!(parse_read_dir_cfm(&cw.bytes[0..cw.count]), Ok(None));
+        cw.send_read_dir_cfm(None, Some(Error::BadOffset)).unwrap();
+        assert_eq!(&cw.bytes[0..cw.count], &[READ_DIR_CFM, 0x05]);
+        let entry = DirEntry {
+            name: b"NAME.THAT.IS.FAR.TOO.LONG",
+            size: 0,
+            mtime: MTIME,
+            file_type: FileType::File,
+        };
+        assert_eq!(
+            cw.send_read_dir_cfm(Some(&entry), None),
+            Err(Error::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn read_dir_cfm_no_terminator() {
+        let payload = [READ_DIR_CFM, 0x00, b'A', b'.', b'T', b'X', b'T'];
+        assert_eq!(parse_read_dir_cfm(&payload), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn dir_requests() {
+        let handle = DirHandle::new(5);
+        let mut cw = CommandWriter::new();
+        cw.send_open_dir_req().unwrap();
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Request(Request::OpenDir))
+        );
+        cw.send_read_dir_req(handle).unwrap();
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Request(Request::ReadDir { handle }))
+        );
+        cw.send_close_dir_req(handle).unwrap();
+        assert_eq!(
+            Message::parse(&cw.bytes[0..cw.count]),
+            Ok(Message::Request(Request::CloseDir { handle }))
+        );
+    }
+}
+
+#[cfg(test)]
+mod integration {
+    use super::*;
+
+    /// Pass the frame in `cw` through a `CommandReader`, and check it decodes
+    /// to `expected`.
+    fn check_frame(cw: &mut CommandWriter, expected: Message) {
+        let mut cr = CommandReader::new();
+        let mut found = false;
+        while let Some(b) = cw.get_byte() {
+            if let Some(payload) = cr.push_byte(b).unwrap() {
+                assert!(!found, "more than one frame");
+                assert_eq!(Message::parse(payload), Ok(expected));
+                found = true;
+            }
+        }
+        assert!(found, "no frame");
+    }
+
+    /// Stands in for the serial link, with the PC at the far end.
+    struct MockTransport {
+        requests: CommandReader,
+        pending_response: Option<CommandWriter>,
+    }
+
+    impl MockTransport {
+        fn new() -> MockTransport {
+            MockTransport {
+                requests: CommandReader::new(),
+                pending_response: None,
+            }
+        }
+
+        /// Send the request in `cw` to the PC.
+        fn send_request(&mut self, cw: &mut CommandWriter) {
+            while let Some(b) = cw.get_byte() {
+                self.requests.push_byte(b).unwrap();
+            }
+        }
+
+        /// Take the payload of the request the PC has just received, which
+        /// must have the given `opcode`.
+        fn expect_request(&mut self, opcode: u8) -> &[u8] {
+            match self.requests.assert_opcode(opcode) {
+                Ok(payload) => payload,
+                Err(e) => panic!("expected request {:#x}, got {:?}", opcode, e),
+            }
+        }
+
+        /// Have the PC reply with the frame in `writer`.
+        fn enqueue_response(&mut self, writer: CommandWriter) {
+            assert!(self.pending_response.is_none(), "response already queued");
+            self.pending_response = Some(writer);
+        }
+
+        /// Deliver the queued response to `reader`, giving its payload.
+        fn feed_bytes<'r>(&mut self, reader: &'r mut CommandReader) -> Option<&'r [u8]> {
+            let mut writer = self.pending_response.take()?;
+            while let Some(b) = writer.get_byte() {
+                reader.push_byte(b).unwrap();
+            }
+            reader.assert_opcode(writer.bytes[0]).ok()
+        }
+    }
+
+    #[test]
+    fn mock_transport_session() {
+        let contents = b"Hello, Monotron";
+        let mut link = MockTransport::new();
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+
+        cw.send_open_file_req(b"HELLO.TXT", Mode::Read).unwrap();
+        link.send_request(&mut cw);
+        assert_eq!(
+            Message::parse(link.expect_request(OPEN_FILE_REQ)),
+            Ok(Message::Request(Request::OpenFile {
+                filename: b"HELLO.TXT",
+                mode: Mode::Read,
+            }))
+        );
+        let mut reply = CommandWriter::new();
+        reply.send_open_cfm(Some(Handle::new(3)), None).unwrap();
+        link.enqueue_response(reply);
+        let handle = parse_open_cfm(link.feed_bytes(&mut cr).unwrap()).unwrap();
+        assert_eq!(handle, Handle::new(3));
+        assert_eq!(link.feed_bytes(&mut cr), None);
+
+        cw.send_read_req(handle, 0).unwrap();
+        link.send_request(&mut cw);
+        link.expect_request(READ_REQ);
+        let mut reply = CommandWriter::new();
+        reply.send_read_cfm(contents, None).unwrap();
+        link.enqueue_response(reply);
+        assert_eq!(
+            parse_read_cfm(link.feed_bytes(&mut cr).unwrap()),
+            Ok(&contents[..])
+        );
+
+        cw.send_close_file_req(handle).unwrap();
+        link.send_request(&mut cw);
+        link.expect_request(CLOSE_FILE_REQ);
+        let mut reply = CommandWriter::new();
+        reply.send_close_cfm(None).unwrap();
+        link.enqueue_response(reply);
+        assert_eq!(parse_close_cfm(link.feed_bytes(&mut cr).unwrap()), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected request")]
+    fn mock_transport_wrong_request() {
+        let mut link = MockTransport::new();
+        let mut cw = CommandWriter::new();
+        cw.send_ping_req();
+        link.send_request(&mut cw);
+        link.expect_request(CLOSE_FILE_REQ);
+    }
+
+    #[test]
+    fn close_all_session() {
+        let mut cw = CommandWriter::new();
+        for (i, name) in [&b"A.TXT"[..], &b"B.TXT"[..]].iter().enumerate() {
+            let handle = Handle::new(i as u8 + 1);
+            cw.send_open_file_req(name, Mode::Read).unwrap();
+            check_frame(
+                &mut cw,
+                Message::Request(Request::OpenFile {
+                    filename: name,
+                    mode: Mode::Read,
+                }),
+            );
+            cw.send_open_cfm(Some(handle), None).unwrap();
+            check_frame(
+                &mut cw,
+                Message::Confirmation(Confirmation::OpenFile {
+                    handle,
+                    error: None,
+                }),
+            );
+        }
+        cw.send_close_all_req().unwrap();
+        check_frame(&mut cw, Message::Request(Request::CloseAll));
+        cw.send_close_all_cfm(2, None).unwrap();
+        assert_eq!(parse_close_all_cfm(&cw.bytes[0..cw.count]), Ok(2));
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::CloseAll {
+                handles_closed: 2,
+                error: None,
+            }),
+        );
+        cw.send_close_all_cfm(0, Some(Error::BadHeader)).unwrap();
+        assert_eq!(
+            parse_close_all_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn sync_retry_session() {
+        let handle = Handle::new(2);
+        let mut link = MockTransport::new();
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+        cw.send_write_file_req(handle, 0, b"12345678").unwrap();
+        link.send_request(&mut cw);
+        assert_eq!(
+            Message::parse(link.expect_request(WRITE_FILE_REQ)),
+            Ok(Message::Request(Request::WriteFile {
+                handle,
+                offset: 0,
+                data: b"12345678",
+            }))
+        );
+        let mut reply = CommandWriter::new();
+        reply.send_write_file_cfm(None).unwrap();
+        link.enqueue_response(reply);
+        assert_eq!(
+            parse_write_file_cfm(link.feed_bytes(&mut cr).unwrap()),
+            Ok(())
+        );
+        let replies = [Some(Error::IoError), None];
+        let mut results = [Ok(()); 2];
+        for (&error, result) in replies.iter().zip(results.iter_mut()) {
+            cw.send_sync_req(handle).unwrap();
+            link.send_request(&mut cw);
+            assert_eq!(link.expect_request(SYNC_REQ), &[SYNC_REQ, handle.to_wire()]);
+            let mut reply = CommandWriter::new();
+            reply.send_flush_cfm(error).unwrap();
+            link.enqueue_response(reply);
+            *result = parse_flush_cfm(link.feed_bytes(&mut cr).unwrap());
+        }
+        assert_eq!(results, [Err(Error::IoError), Ok(())]);
+        assert_eq!(cr.frames_received(), 3);
+    }
+
+    #[test]
+    fn set_baud_rate_session() {
+        const SUPPORTED: [u32; 3] = [9600, 57_600, 115_200];
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+        let mut port_baud = 9600;
+        cw.send_set_baud_rate_req(460_800).unwrap();
+        let requested = match Message::parse(&cw.bytes[0..cw.count]) {
+            Ok(Message::Request(Request::SetBaudRate { baud })) => baud,
+            m => panic!("unexpected {:?}", m),
+        };
+        // Play the part of the PC, which rounds down
+        let accepted = SUPPORTED
+            .iter()
+            .cloned()
+            .filter(|&b| b <= requested)
+            .max()
+            .unwrap();
+        cw.send_set_baud_rate_cfm(accepted, None).unwrap();
+        while let Some(b) = cw.get_byte() {
+            // Still at the old speed while the Confirmation arrives
+            assert_eq!(port_baud, 9600);
+            if let Some(payload) = cr.push_byte(b).unwrap() {
+                port_baud = parse_set_baud_rate_cfm(payload).unwrap();
+            }
+        }
+        assert_eq!(port_baud, 115_200);
+        cw.send_set_baud_rate_cfm(0, Some(Error::BadHeader))
+            .unwrap();
+        assert_eq!(
+            parse_set_baud_rate_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::BadHeader)
+        );
+    }
+
+    #[test]
+    fn write_all_session() {
+        let mut data = [0u8; 100];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let handle = Handle::new(4);
+        let mut session = WriteAllSession::new(handle, &data, 24);
+        let mut cw = CommandWriter::new();
+        let mut file = [0u8; 100];
+        let mut frames = 0;
+        let mut done = false;
+        while let Some(result) = session.next_request(&mut cw) {
+            result.unwrap();
+            assert!(!done);
+            frames += 1;
+            // Play the part of the PC
+            match Message::parse(&cw.bytes[0..cw.count]).unwrap() {
+                Message::Request(Request::WriteFile {
+                    handle: h,
+                    offset,
+                    data,
+                }) => {
+                    assert_eq!(h, handle);
+                    assert!(data.len() <= 24);
+                    let offset = offset as usize;
+                    file[offset..offset + data.len()].copy_from_slice(data);
+                }
+                m => panic!("unexpected {:?}", m),
+            }
+            cw.send_write_file_cfm(None).unwrap();
+            match Message::parse(&cw.bytes[0..cw.count]).unwrap() {
+                Message::Confirmation(cfm) => done = session.handle_confirmation(&cfm).unwrap(),
+                m => panic!("unexpected {:?}", m),
+            }
+        }
+        assert!(done);
+        assert_eq!(frames, 5);
+        assert_eq!(&file[..], &data[..]);
+    }
+
+    #[test]
+    fn write_all_session_error() {
+        let data = [0u8; 30];
+        let mut session = WriteAllSession::new(Handle::new(1), &data, 100);
+        let mut cw = CommandWriter::new();
+        session.next_request(&mut cw).unwrap().unwrap();
+        assert_eq!(cw.count, MAX_PAYLOAD);
+        assert_eq!(
+            session.handle_confirmation(&Confirmation::WriteFile {
+                error: Some(Error::BadOffset)
+            }),
+            Err(Error::BadOffset)
+        );
+        assert_eq!(
+            session.handle_confirmation(&Confirmation::CloseFile { error: None }),
+            Err(Error::BadHeader)
+        );
+        // The same chunk is sent again
+        session.next_request(&mut cw).unwrap().unwrap();
+        assert_eq!(&cw.bytes[2..6], &[0, 0, 0, 0]);
+        assert_eq!(
+            parse_write_file_cfm(&[WRITE_FILE_CFM, 0x05]),
+            Err(Error::BadOffset)
+        );
+    }
+
+    #[test]
+    fn open_dir_session() {
+        let mut cw = CommandWriter::new();
+        cw.send_open_dir_req().unwrap();
+        check_frame(&mut cw, Message::Request(Request::OpenDir));
+        cw.send_open_dir_cfm(Some(DirHandle::new(1)), None).unwrap();
+        let handle = parse_open_dir_cfm(&cw.bytes[0..cw.count]).unwrap();
+        assert!(handle.is_valid());
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::OpenDir {
+                handle,
+                error: None,
+            }),
+        );
+
+        cw.send_open_dir_req().unwrap();
+        check_frame(&mut cw, Message::Request(Request::OpenDir));
+        cw.send_open_dir_cfm(None, Some(Error::FileNotFound))
+            .unwrap();
+        assert_eq!(
+            parse_open_dir_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::FileNotFound)
+        );
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::OpenDir {
+                handle: DirHandle::NULL,
+                error: Some(Error::FileNotFound),
+            }),
+        );
+    }
+
+    #[test]
+    fn file_read_session() {
+        let contents = b"Hello, world";
+        let handle = Handle::new(1);
+        let mut cw = CommandWriter::new();
+
+        cw.send_open_file_req(b"HELLO.TXT", Mode::Read).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Request(Request::OpenFile {
+                filename: b"HELLO.TXT",
+                mode: Mode::Read,
+            }),
+        );
+        cw.send_open_cfm(Some(handle), None).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::OpenFile {
+                handle,
+                error: None,
+            }),
+        );
+
+        cw.send_read_req(handle, 0).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Request(Request::Read { handle, offset: 0 }),
+        );
+        cw.send_read_cfm(&contents[0..8], None).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::Read {
+                data: &contents[0..8],
+                error: None,
+            }),
+        );
+
+        cw.send_read_req(handle, 8).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Request(Request::Read { handle, offset: 8 }),
+        );
+        // Fewer bytes than requested marks the end of the file
+        cw.send_read_cfm(&contents[8..12], None).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::Read {
+                data: &contents[8..12],
+                error: None,
+            }),
+        );
+
+        let checksum = FILE_CHECKSUM.compute(contents).unwrap();
+        cw.send_checksum_req(handle).unwrap();
+        check_frame(&mut cw, Message::Request(Request::Checksum { handle }));
+        cw.send_checksum_cfm(checksum, None).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::Checksum {
+                checksum,
+                error: None,
+            }),
+        );
+
+        cw.send_close_file_req(handle).unwrap();
+        check_frame(&mut cw, Message::Request(Request::CloseFile { handle }));
+        cw.send_close_cfm(None).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Confirmation(Confirmation::CloseFile { error: None }),
+        );
+    }
+
+    #[test]
+    fn read_dir_at_session() {
+        let mtime = Timestamp {
+            year_from_1970: 48,
+            month: 7,
+            days: 4,
+            hours: 13,
+            minutes: 45,
+            seconds: 30,
+        };
+        let listing = [
+            DirEntry {
+                name: b"A.TXT",
+                size: 10,
+                mtime,
+                file_type: FileType::File,
+            },
+            DirEntry {
+                name: b"DOCS",
+                size: 0,
+                mtime,
+                file_type: FileType::Directory,
+            },
+        ];
+        let dir = DirHandle::new(1);
+        let mut link = MockTransport::new();
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+        for index in 0..3 {
+            cw.send_read_dir_at_req(dir, index).unwrap();
+            link.send_request(&mut cw);
+            let asked = match Message::parse(link.expect_request(READ_DIR_AT_REQ)) {
+                Ok(Message::Request(Request::ReadDirAt { handle, index })) => {
+                    assert_eq!(handle, dir);
+                    index
+                }
+                m => panic!("unexpected {:?}", m),
+            };
+            // Play the part of the PC
+            let mut reply = CommandWriter::new();
+            reply
+                .send_read_dir_at_cfm(listing.get(usize::from(asked)), None)
+                .unwrap();
+            link.enqueue_response(reply);
+            let result = parse_read_dir_at_cfm(link.feed_bytes(&mut cr).unwrap());
+            match listing.get(usize::from(index)) {
+                Some(expected) => {
+                    let entry = result.unwrap();
+                    assert_eq!(entry.name, expected.name);
+                    assert_eq!(entry.file_type, expected.file_type);
+                }
+                None => assert_eq!(result.map(|e| e.name), Err(Error::BadOffset)),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn search_files_session() {
+        use std::vec::Vec;
+        let matches = [&b"A.BAS"[..], b"SNAKE.BAS", b"TETRIS.BAS"];
+        let mut cw = CommandWriter::new();
+        cw.send_search_files_req(b"*.BAS").unwrap();
+        check_frame(
+            &mut cw,
+            Message::Request(Request::SearchFiles { pattern: b"*.BAS" }),
+        );
+        // Play the part of the PC, which streams the results back
+        let mut cr = CommandReader::new();
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        for name in matches.iter().map(Some).chain(iter::once(None)) {
+            let entry = name.map(|name| DirEntry {
+                name,
+                size: 100,
+                mtime: Timestamp::FAT_EPOCH,
+                file_type: FileType::File,
+            });
+            cw.send_search_result_cfm(entry.as_ref(), None).unwrap();
+            while let Some(b) = cw.get_byte() {
+                if let Some(payload) = cr.push_byte(b).unwrap() {
+                    frames.push(payload.to_vec());
+                }
+            }
+        }
+        assert_eq!(frames.len(), 4);
+        let mut results: Vec<DirEntry> = Vec::new();
+        let mut frames = frames.iter();
+        while let Some(entry) = parse_search_result_cfm(frames.next().unwrap()).unwrap() {
+            results.push(entry);
+        }
+        assert!(frames.next().is_none());
+        let names: Vec<&[u8]> = results.iter().map(|e| e.name).collect();
+        assert_eq!(&names[..], &matches[..]);
+    }
+
+    #[test]
+    fn file_attributes_session() {
+        let mut attrs_on_pc = FileAttributes::READ_ONLY | FileAttributes::HIDDEN;
+        let mut link = MockTransport::new();
+        let mut cw = CommandWriter::new();
+        let mut cr = CommandReader::new();
+
+        cw.send_get_file_attributes_req(b"SECRET.TXT").unwrap();
+        link.send_request(&mut cw);
+        link.expect_request(GET_FILE_ATTRIBUTES_REQ);
+        let mut reply = CommandWriter::new();
+        reply
+            .send_get_file_attributes_cfm(attrs_on_pc, None)
+            .unwrap();
+        link.enqueue_response(reply);
+        let mut attrs = parse_get_file_attributes_cfm(link.feed_bytes(&mut cr).unwrap()).unwrap();
+        assert!(attrs.contains(FileAttributes::READ_ONLY));
+        assert!(attrs.contains(FileAttributes::HIDDEN));
+        assert!(!attrs.contains(FileAttributes::SYSTEM));
+
+        // Clear the read-only bit, keeping the rest
+        attrs.remove(FileAttributes::READ_ONLY);
+        cw.send_set_file_attributes_req(b"SECRET.TXT", attrs)
+            .unwrap();
+        link.send_request(&mut cw);
+        match Message::parse(link.expect_request(SET_FILE_ATTRIBUTES_REQ)) {
+            Ok(Message::Request(Request::SetFileAttributes { filename, attrs })) => {
+                assert_eq!(filename, b"SECRET.TXT");
+                attrs_on_pc = attrs;
+            }
+            m => panic!("unexpected {:?}", m),
+        }
+        assert_eq!(attrs_on_pc, FileAttributes::HIDDEN);
+        let mut reply = CommandWriter::new();
+        reply.send_set_file_attributes_cfm(None).unwrap();
+        link.enqueue_response(reply);
+        assert_eq!(
+            parse_set_file_attributes_cfm(link.feed_bytes(&mut cr).unwrap()),
+            Ok(())
+        );
+
+        // The disk is write-protected
+        let mut attrs = FileAttributes::empty();
+        attrs.insert(FileAttributes::ARCHIVE);
+        cw.send_set_file_attributes_req(b"LOCKED.TXT", attrs)
+            .unwrap();
+        link.send_request(&mut cw);
+        link.expect_request(SET_FILE_ATTRIBUTES_REQ);
+        let mut reply = CommandWriter::new();
+        reply
+            .send_set_file_attributes_cfm(Some(Error::PermissionDenied))
+            .unwrap();
+        link.enqueue_response(reply);
+        assert_eq!(
+            parse_set_file_attributes_cfm(link.feed_bytes(&mut cr).unwrap()),
+            Err(Error::PermissionDenied)
+        );
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn debug_dump_session() {
+        let mut memory = [0u8; 0x48];
+        for (i, b) in memory.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let base = 0x2000_0000;
+        let mut cw = CommandWriter::new();
+        cw.send_debug_dump_req(base, memory.len() as u32).unwrap();
+        check_frame(
+            &mut cw,
+            Message::Request(Request::DebugDump {
+                address: base,
+                length: 0x48,
+            }),
+        );
+        // Play the part of the PC, sending 0x18 bytes at a time
+        let mut cr = CommandReader::new();
+        let mut addresses = [0u32; 4];
+        let mut received = [0u8; 0x48];
+        let mut frames = 0;
+        let mut done = false;
+        for chunk in memory.chunks(0x18).map(Some).chain(iter::once(None)) {
+            let address = base + (frames * 0x18) as u32;
+            let data = chunk.unwrap_or(&[]);
+            assert!(data.len() <= MAX_DEBUG_DUMP_CHUNK);
+            cw.send_debug_dump_cfm(address, data, None).unwrap();
+            while let Some(b) = cw.get_byte() {
+                if let Some(payload) = cr.push_byte(b).unwrap() {
+                    match parse_debug_dump_cfm(payload).unwrap() {
+                        Some((address, data)) => {
+                            let offset = (address - base) as usize;
+                            received[offset..offset + data.len()].copy_from_slice(data);
+                            addresses[frames] = address;
+                            frames += 1;
+                        }
+                        None => done = true,
+                    }
+                }
+            }
+        }
+        assert!(done);
+        assert_eq!(frames, 3);
+        assert_eq!(&addresses[0..3], &[0x2000_0000, 0x2000_0018, 0x2000_0030]);
+        assert_eq!(&received[..], &memory[..]);
+        assert_eq!(
+            cw.send_debug_dump_cfm(base, &[0u8; MAX_DEBUG_DUMP_CHUNK + 1], None),
+            Err(Error::BufferOverflow)
+        );
+        cw.send_debug_dump_cfm(base, &[], Some(Error::PermissionDenied))
+            .unwrap();
+        assert_eq!(
+            parse_debug_dump_cfm(&cw.bytes[0..cw.count]),
+            Err(Error::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn screen_capture_session() {
+        const WIDTH: usize = 48;
+        const HEIGHT: usize = 36;
+        // The characters, then their attributes
+        let mut screen = [0u8; WIDTH * HEIGHT * 2];
+        for (i, b) in screen.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        let mut cw = CommandWriter::new();
+        cw.send_screen_capture_req().unwrap();
+        check_frame(&mut cw, Message::Request(Request::ScreenCapture));
+        // Play the part of the PC
+        let mut cr = CommandReader::new();
+        let mut received = [0u8; WIDTH * HEIGHT * 2];
+        let mut len = 0;
+        let mut frames = 0;
+        let mut done = false;
+        for chunk in screen
+            .chunks(MAX_SCREEN_CAPTURE_CHUNK)
+            .chain(iter::once(&[][..]))
+        {
+            assert!(!done);
+            cw.send_screen_capture_cfm(chunk, None).unwrap();
+            while let Some(b) = cw.get_byte() {
+                if let Some(payload) = cr.push_byte(b).unwrap() {
+                    let chunk = parse_screen_capture_cfm(payload).unwrap();
+                    if chunk.is_empty() {
+                        done = true;
+                    } else {
+                        received[len..len + chunk.len()].copy_from_slice(chunk);
+                        len += chunk.len();
+                        frames += 1;
+                    }
+                }
+            }
+        }
+        assert!(done);
+        assert_eq!(frames, 144);
+        assert_eq!(len, 3456);
+        assert_eq!(&received[..], &screen[..]);
+        assert_eq!(
+            cw.send_screen_capture_cfm(&[0u8; MAX_SCREEN_CAPTURE_CHUNK + 1], None),
+            Err(Error::BufferOverflow)
+        );
     }
 }